@@ -0,0 +1,16 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use spidev::SpiModeFlags;
+
+fuzz_target!(|input: (u32, u8)| {
+    let (bits, byte) = input;
+
+    match SpiModeFlags::from_mode_bits(bits) {
+        Ok(flags) => assert_eq!(flags.bits(), bits),
+        Err(_) => assert_ne!(bits & !SpiModeFlags::all().bits(), 0),
+    }
+
+    let flags = SpiModeFlags::from_mode_byte(byte);
+    assert_eq!(flags.bits(), u32::from(byte));
+    assert_eq!(SpiModeFlags::from_mode_bits(flags.bits()).unwrap(), flags);
+});
@@ -0,0 +1,142 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Transfer buffers with alignment guarantees
+
+use std::alloc::{self, Layout};
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::slice;
+use libc;
+
+/// A zero-initialized byte buffer whose address has a given alignment
+///
+/// `AlignedBuffer` dereferences to a byte slice, so it can be used
+/// anywhere a transfer buffer is expected.
+///
+/// Note that the spidev driver copies transfer data through its own
+/// kernel buffers (whose size is limited by the `bufsiz` module
+/// parameter), and those are what a DMA-capable controller maps.  The
+/// alignment of a userspace buffer therefore does not decide whether
+/// DMA is used, but a page-aligned buffer never straddles more pages
+/// than necessary, which keeps the kernel's copies and any locking of
+/// the buffer into memory to whole pages.
+pub struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+    layout: Layout,
+}
+
+// The buffer is uniquely owned, like a `Vec<u8>`
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+impl AlignedBuffer {
+    /// Allocate `len` zeroed bytes aligned to `align`
+    ///
+    /// `align` must be a power of two, otherwise an error of kind
+    /// `InvalidInput` is returned.
+    pub fn new(len: usize, align: usize) -> io::Result<AlignedBuffer> {
+        // a zero-sized allocation is not allowed, but an empty buffer
+        // must still have an aligned address
+        let layout = Layout::from_size_align(len.max(1), align).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput,
+                           "alignment must be a power of two")
+        })?;
+        let ptr = match NonNull::new(unsafe { alloc::alloc_zeroed(layout) }) {
+            Some(ptr) => ptr,
+            None => alloc::handle_alloc_error(layout),
+        };
+        Ok(AlignedBuffer { ptr, len, layout })
+    }
+
+    /// Allocate `len` zeroed bytes aligned to the system page size
+    pub fn page_aligned(len: usize) -> io::Result<AlignedBuffer> {
+        AlignedBuffer::new(len, page_size()?)
+    }
+
+    /// The alignment of the buffer's address
+    pub fn align(&self) -> usize {
+        self.layout.align()
+    }
+}
+
+/// The system page size
+pub fn page_size() -> io::Result<usize> {
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if size <= 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(size as usize)
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use super::{page_size, AlignedBuffer};
+    use SpidevTransfer;
+
+    #[test]
+    fn test_aligned_buffer_alignment() {
+        for &align in &[1, 8, 64, 4096] {
+            let buf = AlignedBuffer::new(100, align).unwrap();
+            assert_eq!(buf.as_ptr() as usize % align, 0);
+            assert_eq!(buf.align(), align);
+            assert_eq!(&buf[..], &[0; 100][..]);
+        }
+        let empty = AlignedBuffer::new(0, 64).unwrap();
+        assert_eq!(empty.len(), 0);
+        assert_eq!(empty.as_ptr() as usize % 64, 0);
+    }
+
+    #[test]
+    fn test_aligned_buffer_page_aligned() {
+        let buf = AlignedBuffer::page_aligned(10_000).unwrap();
+        assert_eq!(buf.as_ptr() as usize % page_size().unwrap(), 0);
+        assert_eq!(buf.len(), 10_000);
+    }
+
+    #[test]
+    fn test_aligned_buffer_invalid_align() {
+        let err = AlignedBuffer::new(16, 3).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_aligned_buffer_transfer() {
+        let tx_buf = AlignedBuffer::page_aligned(64).unwrap();
+        let mut rx_buf = AlignedBuffer::page_aligned(64).unwrap();
+        {
+            let mut transfer = SpidevTransfer::read_write(&tx_buf, &mut rx_buf);
+            assert_eq!(transfer.estimated_duration(8, 8_000_000).unwrap().as_micros(), 64);
+            transfer.clear_rx();
+        }
+        assert_eq!(rx_buf.as_ptr() as usize % page_size().unwrap(), 0);
+    }
+}
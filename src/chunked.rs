@@ -0,0 +1,244 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Large transfers split into chunks within one chip select window
+//!
+//! spidev limits each message to its `bufsiz` module parameter (4096
+//! bytes by default), and a long transfer in one ioctl gives no
+//! indication of progress.  The helpers here issue a transfer in
+//! chunks, keeping chip select asserted from the first chunk to the last
+//! so the device sees a single uninterrupted transfer, as flash memories
+//! require for a page program or a read.
+//!
+//! Chip select is held between chunks by setting `cs_change` on all but
+//! the last, which the kernel treats as a hint: it is honored by most
+//! controllers, but another user of the same controller issuing a
+//! message in between would still deselect the device.
+
+use std::io;
+use std::os::unix::prelude::*;
+use std::cmp;
+use spidevioctl::{self, SpidevTransfer};
+use sysfs;
+use Spidev;
+
+/// Perform `tx_buf` in chunks of up to `chunk_size` bytes with `run`,
+/// calling `progress` after each
+fn transfer_chunked_with<R, P>(tx_buf: &[u8],
+                               chunk_size: usize,
+                               mut progress: P,
+                               mut run: R)
+                               -> io::Result<Vec<u8>>
+    where R: FnMut(&mut SpidevTransfer) -> io::Result<()>,
+          P: FnMut(usize, usize)
+{
+    if chunk_size == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "chunk_size must not be zero"));
+    }
+    let total = tx_buf.len();
+    let mut rx_buf = vec![0; total];
+    let mut done = 0;
+    for (tx_chunk, rx_chunk) in tx_buf.chunks(chunk_size).zip(rx_buf.chunks_mut(chunk_size)) {
+        let last = done + tx_chunk.len() == total;
+        let mut transfer = SpidevTransfer::try_read_write(tx_chunk, rx_chunk)?.cs_change(!last);
+        if let Err(err) = run(&mut transfer) {
+            if done > 0 {
+                // deselect the device, which is left selected by the
+                // previous chunk
+                let _ = run(&mut SpidevTransfer::empty());
+            }
+            return Err(err);
+        }
+        done += tx_chunk.len();
+        progress(done, total);
+    }
+    Ok(rx_buf)
+}
+
+/// Perform a full-duplex transfer of `tx_buf` in chunks of up to
+/// `chunk_size` bytes, returning everything received
+///
+/// `progress` is called after each chunk with the number of bytes
+/// transferred so far and the total, e.g. to report progress while
+/// flashing an image.  `chunk_size` must not exceed `sysfs::bufsiz`,
+/// which spidev rejects with `EMSGSIZE`.  Chip select stays asserted
+/// across chunks; see the module documentation.  If a chunk fails the
+/// device is deselected and the error returned.
+pub fn transfer_chunked<P>(fd: RawFd, tx_buf: &[u8], chunk_size: usize, progress: P) -> io::Result<Vec<u8>>
+    where P: FnMut(usize, usize)
+{
+    transfer_chunked_with(tx_buf, chunk_size, progress, |transfer| {
+        spidevioctl::transfer(fd, transfer).map(|_| ())
+    })
+}
+
+/// As `transfer_chunked`, running each chunk through `Spidev::transfer`
+/// so that the per-handle defaults apply
+pub(crate) fn transfer_chunked_spidev<P>(spidev: &Spidev,
+                                         tx_buf: &[u8],
+                                         chunk_size: usize,
+                                         progress: P)
+                                         -> io::Result<Vec<u8>>
+    where P: FnMut(usize, usize)
+{
+    transfer_chunked_with(tx_buf, chunk_size, progress, |transfer| spidev.transfer(transfer))
+}
+
+/// Write `count` copies of `byte` with `run`, in chunks of up to
+/// `chunk_size` bytes sent from a single buffer of that size
+fn write_repeated_with<R>(byte: u8, count: usize, chunk_size: usize, mut run: R) -> io::Result<()>
+    where R: FnMut(&mut SpidevTransfer) -> io::Result<()>
+{
+    let buf = vec![byte; cmp::min(count, chunk_size)];
+    let mut remaining = count;
+    while remaining > 0 {
+        let len = cmp::min(remaining, buf.len());
+        let mut transfer = SpidevTransfer::write(&buf[..len]).cs_change(len != remaining);
+        if let Err(err) = run(&mut transfer) {
+            if remaining != count {
+                let _ = run(&mut SpidevTransfer::empty());
+            }
+            return Err(err);
+        }
+        remaining -= len;
+    }
+    Ok(())
+}
+
+/// Write `count` copies of `byte`, e.g. to fill a display with a solid
+/// color or to pad a flash page
+///
+/// Only one buffer of up to spidev's message size limit
+/// (`sysfs::bufsiz`) is allocated and sent repeatedly, however large
+/// `count` is.  Chip select stays asserted across the chunks; see the
+/// module documentation.
+pub fn write_repeated(fd: RawFd, byte: u8, count: usize) -> io::Result<()> {
+    write_repeated_with(byte, count, sysfs::bufsiz(), |transfer| {
+        spidevioctl::transfer(fd, transfer).map(|_| ())
+    })
+}
+
+/// As `write_repeated`, running each chunk through `Spidev::transfer`
+pub(crate) fn write_repeated_spidev(spidev: &Spidev, byte: u8, count: usize) -> io::Result<()> {
+    write_repeated_with(byte, count, sysfs::bufsiz(), |transfer| spidev.transfer(transfer))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use libc;
+    use super::{transfer_chunked_with, write_repeated_with};
+    use test::test_device;
+    use SpidevTransfer;
+
+    #[test]
+    fn test_transfer_chunked() {
+        let tx_buf: Vec<u8> = (0..10u8).collect();
+        let mut progress = Vec::new();
+        let mut cs_changes = Vec::new();
+        let rx_buf = transfer_chunked_with(&tx_buf,
+                                           4,
+                                           |done, total| progress.push((done, total)),
+                                           |transfer| {
+                                               // loop the data back, inverted
+                                               let tx: Vec<u8> = transfer.tx_slice().to_vec();
+                                               for (rx, tx) in transfer.rx_slice_mut().iter_mut().zip(tx) {
+                                                   *rx = !tx;
+                                               }
+                                               cs_changes.push(transfer.cs_change);
+                                               Ok(())
+                                           })
+                         .unwrap();
+        assert_eq!(progress, [(4, 10), (8, 10), (10, 10)]);
+        // chip select is only released after the last chunk
+        assert_eq!(cs_changes, [1, 1, 0]);
+        assert_eq!(rx_buf, tx_buf.iter().map(|&b| !b).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_transfer_chunked_failure() {
+        let tx_buf = [0; 10];
+        let mut lens = Vec::new();
+        let mut calls = 0;
+        let result = transfer_chunked_with(&tx_buf, 4, |_, _| calls += 1, |transfer| {
+            lens.push(transfer.tx_slice().len());
+            if lens.len() == 2 {
+                Err(io::Error::from_raw_os_error(libc::EIO))
+            } else {
+                Ok(())
+            }
+        });
+        assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EIO));
+        assert_eq!(calls, 1);
+        // the failed chunk is followed by an empty transfer releasing CS
+        assert_eq!(lens, [4, 4, 0]);
+        assert!(transfer_chunked_with(&tx_buf, 0, |_, _| {}, |_| Ok(())).is_err());
+    }
+
+    #[test]
+    fn test_write_repeated() {
+        let mut received = Vec::new();
+        let mut chunks = Vec::new();
+        write_repeated_with(0xa5, 10_000, 4096, |transfer| {
+            // stands in for a loopback, collecting what was clocked out
+            let tx = transfer.tx_slice();
+            received.extend_from_slice(tx);
+            chunks.push((tx.as_ptr() as usize, tx.len(), transfer.cs_change));
+            Ok(())
+        })
+            .unwrap();
+        assert_eq!(received.len(), 10_000);
+        assert!(received.iter().all(|&byte| byte == 0xa5));
+        assert_eq!(chunks.iter().map(|chunk| (chunk.1, chunk.2)).collect::<Vec<_>>(),
+                   [(4096, 1), (4096, 1), (1808, 0)]);
+        // the same buffer is sent each time
+        assert!(chunks.iter().all(|chunk| chunk.0 == chunks[0].0));
+
+        let mut calls = 0;
+        write_repeated_with(0, 0, 4096, |_| {
+            calls += 1;
+            Ok(())
+        })
+            .unwrap();
+        assert_eq!(calls, 0);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_write_repeated_device() {
+        let spidev = test_device();
+        let count = 3 * 4096 + 17;
+        spidev.write_repeated(0xa5, count).unwrap();
+
+        // clock each chunk out full-duplex to see what was sent
+        let mut received = Vec::new();
+        write_repeated_with(0xa5, count, 4096, |transfer| {
+            let tx_buf = transfer.tx_slice().to_vec();
+            let mut rx_buf = vec![0; tx_buf.len()];
+            spidev.transfer(&mut SpidevTransfer::read_write(&tx_buf, &mut rx_buf)
+                                 .cs_change(transfer.cs_change != 0))?;
+            received.extend_from_slice(&rx_buf);
+            Ok(())
+        })
+            .unwrap();
+        // requires MOSI to be looped back to MISO
+        assert_eq!(received, vec![0xa5; count]);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_transfer_chunked_device() {
+        let spidev = test_device();
+        let tx_buf: Vec<u8> = (0..10_000).map(|i| i as u8).collect();
+        let mut chunks = 0;
+        let rx_buf = spidev.transfer_chunked(&tx_buf, 4096, |_, _| chunks += 1).unwrap();
+        assert_eq!(chunks, 3);
+        // requires MOSI to be looped back to MISO
+        assert_eq!(rx_buf, tx_buf);
+    }
+}
@@ -0,0 +1,1275 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Higher-level helpers for reading and changing device configuration
+//!
+//! These are built on the individual configuration ioctls in
+//! `spidevioctl`.
+
+use libc;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::os::unix::prelude::*;
+use spidevioctl::{self, SpidevTransfer};
+use super::{SpiModeFlags, SpidevOptions};
+use sysfs;
+
+/// The configuration ioctls, abstracted so that the helpers in this
+/// module can be exercised against a mock device in tests
+pub(crate) trait Control {
+    fn mode(&self) -> io::Result<SpiModeFlags>;
+    fn set_mode(&self, mode: SpiModeFlags) -> io::Result<()>;
+    /// The whole 32-bit mode, including bits `SpiModeFlags` doesn't define
+    fn mode32(&self) -> io::Result<u32>;
+    fn set_mode32(&self, mode: u32) -> io::Result<()>;
+    fn lsb_first(&self) -> io::Result<bool>;
+    fn set_lsb_first(&self, lsb_first: bool) -> io::Result<()>;
+    fn bits_per_word(&self) -> io::Result<u8>;
+    fn set_bits_per_word(&self, bits_per_word: u8) -> io::Result<()>;
+    fn max_speed_hz(&self) -> io::Result<u32>;
+    fn set_max_speed_hz(&self, max_speed_hz: u32) -> io::Result<()>;
+    fn transfer_multiple(&self, transfers: &mut [SpidevTransfer]) -> io::Result<u32>;
+}
+
+impl Control for RawFd {
+    fn mode(&self) -> io::Result<SpiModeFlags> {
+        // bits this crate does not know about are dropped
+        Ok(SpiModeFlags::from_bits_truncate(spidevioctl::get_mode32(*self)?))
+    }
+
+    fn set_mode(&self, mode: SpiModeFlags) -> io::Result<()> {
+        spidevioctl::set_mode(*self, mode)
+    }
+
+    fn mode32(&self) -> io::Result<u32> {
+        spidevioctl::get_mode32(*self)
+    }
+
+    fn set_mode32(&self, mode: u32) -> io::Result<()> {
+        spidevioctl::set_mode32(*self, mode)
+    }
+
+    fn lsb_first(&self) -> io::Result<bool> {
+        Ok(spidevioctl::get_lsb_first(*self)? != 0)
+    }
+
+    fn set_lsb_first(&self, lsb_first: bool) -> io::Result<()> {
+        spidevioctl::set_lsb_first(*self, lsb_first)
+    }
+
+    fn bits_per_word(&self) -> io::Result<u8> {
+        spidevioctl::get_bits_per_word(*self)
+    }
+
+    fn set_bits_per_word(&self, bits_per_word: u8) -> io::Result<()> {
+        spidevioctl::set_bits_per_word(*self, bits_per_word)
+    }
+
+    fn max_speed_hz(&self) -> io::Result<u32> {
+        spidevioctl::get_max_speed_hz(*self)
+    }
+
+    fn set_max_speed_hz(&self, max_speed_hz: u32) -> io::Result<()> {
+        spidevioctl::set_max_speed_hz(*self, max_speed_hz)
+    }
+
+    fn transfer_multiple(&self, transfers: &mut [SpidevTransfer]) -> io::Result<u32> {
+        spidevioctl::transfer_multiple(*self, transfers)
+    }
+}
+
+/// A setting that read back differently from the value written to it
+///
+/// Returned inside an `io::Error` of kind `InvalidData` when writes are
+/// verified, as by `set_and_verify`; it can be recovered with
+/// `io::Error::get_ref` and `downcast_ref`.  Boolean settings are
+/// given as 0 or 1 and the mode as its bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mismatch {
+    pub setting: Setting,
+    pub requested: u32,
+    pub actual: u32,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{} read back as {} after writing {}",
+               self.setting,
+               self.actual,
+               self.requested)
+    }
+}
+
+impl Error for Mismatch {}
+
+impl From<Mismatch> for io::Error {
+    fn from(mismatch: Mismatch) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, mismatch)
+    }
+}
+
+/// A device whose settings are read back after each write, failing with
+/// a `Mismatch` if the value read differs from the one written
+pub(crate) struct Verified<'a, C: Control + 'a>(pub &'a C);
+
+impl<'a, C: Control> Verified<'a, C> {
+    fn check(setting: Setting, requested: u32, actual: u32) -> io::Result<()> {
+        if requested != actual {
+            return Err(Mismatch {
+                           setting,
+                           requested,
+                           actual,
+                       }
+                       .into());
+        }
+        Ok(())
+    }
+}
+
+impl<'a, C: Control> Control for Verified<'a, C> {
+    fn mode(&self) -> io::Result<SpiModeFlags> {
+        self.0.mode()
+    }
+
+    fn set_mode(&self, mode: SpiModeFlags) -> io::Result<()> {
+        self.0.set_mode(mode)?;
+        Self::check(Setting::Mode, mode.bits(), self.0.mode()?.bits())
+    }
+
+    fn mode32(&self) -> io::Result<u32> {
+        self.0.mode32()
+    }
+
+    fn set_mode32(&self, mode: u32) -> io::Result<()> {
+        self.0.set_mode32(mode)?;
+        Self::check(Setting::Mode, mode, self.0.mode32()?)
+    }
+
+    fn lsb_first(&self) -> io::Result<bool> {
+        self.0.lsb_first()
+    }
+
+    fn set_lsb_first(&self, lsb_first: bool) -> io::Result<()> {
+        self.0.set_lsb_first(lsb_first)?;
+        Self::check(Setting::LsbFirst, lsb_first as u32, self.0.lsb_first()? as u32)
+    }
+
+    fn bits_per_word(&self) -> io::Result<u8> {
+        self.0.bits_per_word()
+    }
+
+    fn set_bits_per_word(&self, bits_per_word: u8) -> io::Result<()> {
+        self.0.set_bits_per_word(bits_per_word)?;
+        Self::check(Setting::BitsPerWord,
+                    u32::from(bits_per_word),
+                    u32::from(self.0.bits_per_word()?))
+    }
+
+    fn max_speed_hz(&self) -> io::Result<u32> {
+        self.0.max_speed_hz()
+    }
+
+    fn set_max_speed_hz(&self, max_speed_hz: u32) -> io::Result<()> {
+        self.0.set_max_speed_hz(max_speed_hz)?;
+        Self::check(Setting::MaxSpeedHz, max_speed_hz, self.0.max_speed_hz()?)
+    }
+
+    fn transfer_multiple(&self, transfers: &mut [SpidevTransfer]) -> io::Result<u32> {
+        self.0.transfer_multiple(transfers)
+    }
+}
+
+/// Which settings were written to the device by `apply_diff`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChangeSet {
+    pub bits_per_word: bool,
+    pub max_speed_hz: bool,
+    pub lsb_first: bool,
+    pub spi_mode: bool,
+}
+
+impl ChangeSet {
+    /// Whether nothing was changed
+    pub fn is_empty(&self) -> bool {
+        *self == ChangeSet::default()
+    }
+}
+
+/// One of the settings written when applying options
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Setting {
+    BitsPerWord,
+    MaxSpeedHz,
+    Mode,
+    LsbFirst,
+}
+
+impl fmt::Display for Setting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Setting::BitsPerWord => "bits_per_word",
+            Setting::MaxSpeedHz => "max_speed_hz",
+            Setting::Mode => "mode",
+            Setting::LsbFirst => "lsb_first",
+        })
+    }
+}
+
+/// Writing one setting failed part way through applying options
+///
+/// Returned when a `ConfigTransaction` is committed or by
+/// `configure_and_transfer`, inside an `io::Error` of the same kind as
+/// `error`; it can be recovered with
+/// `io::Error::get_ref` and `downcast_ref`.
+#[derive(Debug)]
+pub struct StepFailed {
+    /// The setting that could not be written
+    pub setting: Setting,
+    /// The settings written before it, which remain applied
+    pub applied: ChangeSet,
+    /// The error from writing it
+    pub error: io::Error,
+}
+
+impl StepFailed {
+    /// The options of `desired` that were written before the failure
+    ///
+    /// The handle-only `delay_usecs` needs no write, so it is kept.
+    pub(crate) fn applied_options(&self, desired: &SpidevOptions) -> SpidevOptions {
+        SpidevOptions {
+            bits_per_word: desired.bits_per_word.filter(|_| self.applied.bits_per_word),
+            max_speed_hz: desired.max_speed_hz.filter(|_| self.applied.max_speed_hz),
+            lsb_first: desired.lsb_first.filter(|_| self.applied.lsb_first),
+            spi_mode: desired.spi_mode.filter(|_| self.applied.spi_mode),
+            delay_usecs: desired.delay_usecs,
+        }
+    }
+}
+
+impl fmt::Display for StepFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "failed to set {}: {}", self.setting, self.error)
+    }
+}
+
+impl Error for StepFailed {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<StepFailed> for io::Error {
+    fn from(failed: StepFailed) -> io::Error {
+        io::Error::new(failed.error.kind(), failed)
+    }
+}
+
+/// Read the current values of the options that are set in `desired`
+fn current_options<C: Control>(dev: &C, desired: &SpidevOptions) -> io::Result<SpidevOptions> {
+    let mut current = SpidevOptions::new();
+    if desired.bits_per_word.is_some() {
+        current.bits_per_word = Some(dev.bits_per_word()?);
+    }
+    if desired.max_speed_hz.is_some() {
+        current.max_speed_hz = Some(dev.max_speed_hz()?);
+    }
+    if desired.lsb_first.is_some() {
+        current.lsb_first = Some(dev.lsb_first()?);
+    }
+    if desired.spi_mode.is_some() {
+        current.spi_mode = Some(dev.mode()?);
+    }
+    Ok(current)
+}
+
+/// Write the options in `desired` that differ from `current`,
+/// identifying the setting that failed if any does
+fn write_steps<C: Control>(dev: &C,
+                           current: &SpidevOptions,
+                           desired: &SpidevOptions)
+                           -> Result<ChangeSet, StepFailed> {
+    let mut changes = ChangeSet::default();
+    let failed = |setting, applied, error| {
+        StepFailed {
+            setting,
+            applied,
+            error,
+        }
+    };
+    if let Some(bits_per_word) = desired.bits_per_word {
+        if current.bits_per_word != Some(bits_per_word) {
+            dev.set_bits_per_word(bits_per_word)
+               .map_err(|err| failed(Setting::BitsPerWord, changes, err))?;
+            changes.bits_per_word = true;
+        }
+    }
+    if let Some(max_speed_hz) = desired.max_speed_hz {
+        if current.max_speed_hz != Some(max_speed_hz) {
+            dev.set_max_speed_hz(max_speed_hz)
+               .map_err(|err| failed(Setting::MaxSpeedHz, changes, err))?;
+            changes.max_speed_hz = true;
+        }
+    }
+    // the mode is written first as it includes the LSB_FIRST bit.  A
+    // desired lsb_first takes precedence over that bit, so it is folded
+    // into the mode both to compare against the current mode and to be
+    // written along with it
+    let mut lsb_first = current.lsb_first;
+    if let Some(mut mode) = desired.spi_mode {
+        if let Some(desired_lsb_first) = desired.lsb_first {
+            mode.set(SpiModeFlags::SPI_LSB_FIRST, desired_lsb_first);
+        }
+        if current.spi_mode != Some(mode) {
+            dev.set_mode(mode).map_err(|err| failed(Setting::Mode, changes, err))?;
+            changes.spi_mode = true;
+            let written = Some(mode.contains(SpiModeFlags::SPI_LSB_FIRST));
+            changes.lsb_first = desired.lsb_first.is_some() && written != lsb_first;
+            lsb_first = written;
+        }
+    }
+    if let Some(desired_lsb_first) = desired.lsb_first {
+        if lsb_first != Some(desired_lsb_first) {
+            dev.set_lsb_first(desired_lsb_first)
+               .map_err(|err| failed(Setting::LsbFirst, changes, err))?;
+            changes.lsb_first = true;
+        }
+    }
+    Ok(changes)
+}
+
+/// Write the options in `desired` that differ from `current`
+fn write_changes<C: Control>(dev: &C,
+                             current: &SpidevOptions,
+                             desired: &SpidevOptions)
+                             -> io::Result<ChangeSet> {
+    write_steps(dev, current, desired).map_err(|failed| failed.error)
+}
+
+/// Write back the `previous` values of the options in `changes`
+fn restore<C: Control>(dev: &C, previous: &SpidevOptions, changes: &ChangeSet) -> io::Result<()> {
+    if let (true, Some(bits_per_word)) = (changes.bits_per_word, previous.bits_per_word) {
+        dev.set_bits_per_word(bits_per_word)?;
+    }
+    if let (true, Some(max_speed_hz)) = (changes.max_speed_hz, previous.max_speed_hz) {
+        dev.set_max_speed_hz(max_speed_hz)?;
+    }
+    // the previous mode includes the previous LSB_FIRST bit, so
+    // restoring it restores both
+    if let (true, Some(mode)) = (changes.spi_mode, previous.spi_mode) {
+        dev.set_mode(mode)?;
+    } else if let (true, Some(lsb_first)) = (changes.lsb_first, previous.lsb_first) {
+        dev.set_lsb_first(lsb_first)?;
+    }
+    Ok(())
+}
+
+/// Write every option that is set in `options`, as `Spidev::configure`
+/// does, stopping at the first that fails
+///
+/// This is `write_steps` against an unknown current state, so nothing is
+/// skipped, except that a desired lsb_first is written as part of the
+/// mode when both are set.
+pub(crate) fn configure_with<C: Control>(dev: &C, options: &SpidevOptions) -> Result<ChangeSet, StepFailed> {
+    write_steps(dev, &SpidevOptions::new(), options)
+}
+
+/// Write every option that is set in `options`, reading each back to
+/// check that it took effect
+///
+/// This catches controllers that silently ignore or clamp a setting: a
+/// value that reads back differently fails with a `Mismatch`, leaving
+/// the options written before it applied.  Each setting costs a second
+/// ioctl, so this is opt-in; `Spidev::set_verify_writes` enables it for
+/// the configuration methods of a handle.  Whether a clamped speed is
+/// visible depends on the kernel; see `nearest_achievable_speed`.
+pub fn set_and_verify(fd: RawFd, options: &SpidevOptions) -> io::Result<()> {
+    configure_with(&Verified(&fd), options).map(|_| ()).map_err(|failed| failed.error)
+}
+
+pub(crate) fn current_state_with<C: Control>(dev: &C) -> io::Result<SpidevOptions> {
+    Ok(SpidevOptions {
+        bits_per_word: Some(dev.bits_per_word()?),
+        max_speed_hz: Some(dev.max_speed_hz()?),
+        lsb_first: Some(dev.lsb_first()?),
+        spi_mode: Some(dev.mode()?),
+        delay_usecs: None,
+    })
+}
+
+/// Read the device's whole configuration from the kernel
+///
+/// Every option other than `delay_usecs`, which is not stored by the
+/// kernel, is set in the result.  This is useful after `configure`
+/// fails part way, as the options before the failing one will already
+/// have been written: the result shows exactly what the device is now
+/// using, and passing it to `configure` puts it back after another
+/// attempt.
+pub fn current_state(fd: RawFd) -> io::Result<SpidevOptions> {
+    current_state_with(&fd)
+}
+
+pub(crate) fn apply_diff_with<C: Control>(dev: &C, desired: &SpidevOptions) -> io::Result<ChangeSet> {
+    write_changes(dev, &current_options(dev, desired)?, desired)
+}
+
+/// Like `apply_diff_with`, but a failed write is reported as a
+/// `StepFailed`
+pub(crate) fn commit_with<C: Control>(dev: &C, desired: &SpidevOptions) -> io::Result<ChangeSet> {
+    Ok(write_steps(dev, &current_options(dev, desired)?, desired)?)
+}
+
+/// Apply only those options that differ from the device's current state
+///
+/// Each option that is set in `desired` is read back from the device and
+/// written only if it differs, which avoids needless reconfiguration
+/// when switching between mostly similar configurations.  Reading a
+/// setting is cheap compared to changing it, which may cause the
+/// controller to be reprogrammed.
+pub fn apply_diff(fd: RawFd, desired: &SpidevOptions) -> io::Result<ChangeSet> {
+    apply_diff_with(&fd, desired)
+}
+
+/// The settings of one of several logical devices sharing a spidev
+/// device, e.g. behind an external chip select multiplexer
+///
+/// Activating a profile writes only the settings that differ from the
+/// current ones, so switching between devices with similar settings is
+/// cheap.  Settings a profile leaves unset keep whatever value was last
+/// written, so profiles that share a device should all set the same
+/// options.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceProfile {
+    options: SpidevOptions,
+}
+
+impl DeviceProfile {
+    /// A profile applying `options`
+    pub fn new(options: SpidevOptions) -> DeviceProfile {
+        DeviceProfile { options }
+    }
+
+    /// The options applied by this profile
+    pub fn options(&self) -> &SpidevOptions {
+        &self.options
+    }
+}
+
+impl From<SpidevOptions> for DeviceProfile {
+    fn from(options: SpidevOptions) -> DeviceProfile {
+        DeviceProfile::new(options)
+    }
+}
+
+/// Apply `profile`, returning which settings had to be written
+pub fn activate(fd: RawFd, profile: &DeviceProfile) -> io::Result<ChangeSet> {
+    apply_diff(fd, &profile.options)
+}
+
+/// `configure_and_transfer`, performing the transfer with `run` once
+/// the options have been applied
+///
+/// A failed write is reported as a `StepFailed`, after restoring the
+/// settings written before it if `restore_after` is set.
+pub(crate) fn configure_and_transfer_with<C, F, T>(dev: &C,
+                                                   options: &SpidevOptions,
+                                                   restore_after: bool,
+                                                   run: F)
+                                                   -> io::Result<(T, ChangeSet)>
+    where C: Control,
+          F: FnOnce() -> io::Result<T>
+{
+    let previous = current_options(dev, options)?;
+    let changes = match write_steps(dev, &previous, options) {
+        Ok(changes) => changes,
+        Err(failed) => {
+            if restore_after {
+                // the failed write is the error worth reporting
+                let _ = restore(dev, &previous, &failed.applied);
+            }
+            return Err(failed.into());
+        }
+    };
+    let result = run();
+    if restore_after {
+        // restore even if the transfer failed, but report its error first
+        let restored = restore(dev, &previous, &changes);
+        let value = result?;
+        restored?;
+        return Ok((value, changes));
+    }
+    Ok((result?, changes))
+}
+
+/// Apply the options that differ from the device's state, then transfer
+///
+/// This is `apply_diff` followed by `spidevioctl::transfer`, for buses
+/// shared by devices that each need a different mode or speed: only the
+/// settings that actually differ cost an ioctl.  With `restore_after`
+/// the settings that were changed are written back after the transfer,
+/// whether or not it succeeded; otherwise they are left as configured.
+/// Returns the number of bytes transferred.
+///
+/// If writing a setting fails, the transfer is not attempted and the
+/// error carries a `StepFailed`.  With `restore_after` the settings
+/// written before the failure are restored first.
+pub fn configure_and_transfer(fd: RawFd,
+                              options: &SpidevOptions,
+                              transfer: &mut SpidevTransfer,
+                              restore_after: bool)
+                              -> io::Result<u32> {
+    configure_and_transfer_with(&fd, options, restore_after, || {
+        fd.transfer_multiple(std::slice::from_mut(transfer))
+    })
+        .map(|(bytes, _)| bytes)
+}
+
+/// Restores a device's mode if dropped while still armed, i.e. when
+/// unwinding out of the closure given to `with_mode`
+struct RestoreMode<'a, C: Control + 'a> {
+    dev: &'a C,
+    previous: u32,
+    armed: bool,
+}
+
+impl<'a, C: Control> Drop for RestoreMode<'a, C> {
+    fn drop(&mut self) {
+        if self.armed {
+            // nothing can be done about a failure while panicking
+            let _ = self.dev.set_mode32(self.previous);
+        }
+    }
+}
+
+pub(crate) fn with_mode_with<C, F, T>(dev: &C, mode: SpiModeFlags, f: F) -> io::Result<T>
+    where C: Control,
+          F: FnOnce() -> io::Result<T>
+{
+    // the raw mode, so restoring it keeps bits SpiModeFlags doesn't define
+    let previous = dev.mode32()?;
+    dev.set_mode(mode)?;
+    let mut guard = RestoreMode {
+        dev,
+        previous,
+        armed: true,
+    };
+    let result = f();
+    guard.armed = false;
+    let restored = dev.set_mode32(previous);
+    let value = result?;
+    restored?;
+    Ok(value)
+}
+
+/// Run `f` with the device in `mode`, then restore the previous mode
+///
+/// The previous mode is restored however `f` finishes: if it returns an
+/// error, that error is returned after restoring, and if it panics the
+/// mode is restored while unwinding.  A failure to restore the mode is
+/// only reported when `f` succeeded.
+pub fn with_mode<F, T>(fd: RawFd, mode: SpiModeFlags, f: F) -> io::Result<T>
+    where F: FnOnce() -> io::Result<T>
+{
+    with_mode_with(&fd, mode, f)
+}
+
+/// `set_mode_preserving_options`, returning the whole 32-bit mode written
+pub(crate) fn set_mode_preserving_options_with<C: Control>(dev: &C, mode: SpiModeFlags) -> io::Result<u32> {
+    let clock_bits = SpiModeFlags::SPI_MODE_3.bits();
+    // the raw mode, so that bits SpiModeFlags doesn't define survive
+    let current = dev.mode32()?;
+    let mode = (current & !clock_bits) | (mode.bits() & clock_bits);
+    dev.set_mode32(mode)?;
+    Ok(mode)
+}
+
+/// Change only the CPOL/CPHA bits of the mode
+///
+/// The current mode is read back and only its clock polarity and phase
+/// are replaced, so option bits such as `SPI_CS_HIGH` or
+/// `SPI_LSB_FIRST` are preserved, as are any that `SpiModeFlags` has no
+/// flag for.  Any bits of `mode` other than
+/// `SPI_CPOL` and `SPI_CPHA` are ignored.
+pub fn set_mode_preserving_options(fd: RawFd, mode: SpiModeFlags) -> io::Result<()> {
+    set_mode_preserving_options_with(&fd, mode).map(|_| ())
+}
+
+pub(crate) fn supported_modes_with<C: Control>(dev: &C) -> io::Result<Vec<SpiModeFlags>> {
+    let clock_bits = SpiModeFlags::SPI_MODE_3.bits();
+    // the raw mode, so that bits SpiModeFlags doesn't define survive
+    let original = dev.mode32()?;
+    let mut supported = Vec::new();
+    for &mode in &[SpiModeFlags::SPI_MODE_0,
+                   SpiModeFlags::SPI_MODE_1,
+                   SpiModeFlags::SPI_MODE_2,
+                   SpiModeFlags::SPI_MODE_3] {
+        if dev.set_mode32((original & !clock_bits) | mode.bits()).is_ok() {
+            supported.push(mode);
+        }
+    }
+    dev.set_mode32(original)?;
+    Ok(supported)
+}
+
+/// List which of `SPI_MODE_0` through `SPI_MODE_3` the controller accepts
+///
+/// Each mode is probed by setting it, keeping the current option bits,
+/// and the original mode is restored afterwards whatever the outcome.
+/// The kernel rejects modes the controller driver doesn't support when
+/// they are set, so this reflects the driver's advertised capabilities.
+pub fn supported_modes(fd: RawFd) -> io::Result<Vec<SpiModeFlags>> {
+    supported_modes_with(&fd)
+}
+
+pub(crate) fn nearest_achievable_speed_with<C: Control>(dev: &C, requested_hz: u32) -> io::Result<u32> {
+    let prior = dev.max_speed_hz()?;
+    let achieved = dev.set_max_speed_hz(requested_hz).and_then(|()| dev.max_speed_hz());
+    // restore the prior speed even if the probe failed
+    let restored = dev.set_max_speed_hz(prior);
+    let achieved = achieved?;
+    restored?;
+    Ok(achieved)
+}
+
+/// Find the speed the kernel settles on for a requested maximum speed
+///
+/// The speed is written, read back and then restored to its prior
+/// value, so this reports what the kernel stores for the request and
+/// fails if the controller rejects it.  How much that reveals depends on
+/// the kernel: some versions store the speed as clamped by the
+/// controller, while others report the requested value unchanged.  Most
+/// controllers only round to an available clock divisor when each
+/// transfer starts, which is not visible to userspace, so the result is
+/// an upper bound on the actual clock rate.
+pub fn nearest_achievable_speed(fd: RawFd, requested_hz: u32) -> io::Result<u32> {
+    nearest_achievable_speed_with(&fd, requested_hz)
+}
+
+/// Compute the integer divider of `base_hz` for a requested speed and
+/// the speed it results in, as `(divider, actual_hz)`
+///
+/// The divider is the smallest that doesn't exceed `requested_hz`, so
+/// the actual speed is at most the requested one, the way controllers
+/// generally round.  Requesting zero fails with `InvalidInput`.
+pub fn clock_divider_for(base_hz: u64, requested_hz: u32) -> io::Result<(u32, u32)> {
+    if requested_hz == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "requested speed must not be zero"));
+    }
+    let requested = u64::from(requested_hz);
+    let divider = (base_hz / requested + u64::from(base_hz % requested != 0)).max(1);
+    let divider = u32::try_from(divider).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput,
+                       "requested speed needs a divider larger than 32 bits")
+    })?;
+    // at most requested_hz, or base_hz if that is lower
+    Ok((divider, (base_hz / u64::from(divider)) as u32))
+}
+
+/// Compute the clock divider the controller will use for `requested_hz`
+///
+/// The controller's base clock is read with `sysfs::base_clock_hz`,
+/// which fails with `NotFound` for controllers that don't expose it.
+/// This models a plain integer divider; controllers restricted to even
+/// or power-of-two dividers round further, so treat `actual_hz` as an
+/// upper bound there.
+pub fn clock_divider(fd: RawFd, requested_hz: u32) -> io::Result<(u32, u32)> {
+    clock_divider_for(sysfs::base_clock_hz(fd)?, requested_hz)
+}
+
+pub(crate) fn supports_lsb_first_with<C: Control>(dev: &C) -> io::Result<bool> {
+    let prior = dev.lsb_first()?;
+    let honored = match dev.set_lsb_first(true) {
+        Ok(()) => dev.lsb_first(),
+        Err(ref err) if err.raw_os_error() == Some(libc::EINVAL) => Ok(false),
+        Err(err) => Err(err),
+    };
+    // restore the prior bit order even if the probe failed
+    let restored = dev.set_lsb_first(prior);
+    let honored = honored?;
+    restored?;
+    Ok(honored)
+}
+
+/// Check whether the controller honors `SPI_LSB_FIRST`
+///
+/// LSB-first is set and read back, and the prior bit order is restored
+/// afterwards.  Current kernels reject mode bits the controller doesn't
+/// support with `EINVAL`, which is reported as `false`; a driver that
+/// accepts the bit but drops it is caught by the read back.
+pub fn supports_lsb_first(fd: RawFd) -> io::Result<bool> {
+    supports_lsb_first_with(&fd)
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::cell::{Cell, RefCell};
+    use std::io;
+    use libc;
+    use std::panic::{self, AssertUnwindSafe};
+    use super::{apply_diff_with, clock_divider_for, commit_with, configure_and_transfer_with, configure_with,
+                current_state_with, DeviceProfile, Mismatch, Setting, StepFailed, Verified,
+                nearest_achievable_speed_with,
+                set_mode_preserving_options_with, supported_modes_with, supports_lsb_first_with,
+                with_mode_with, ChangeSet, Control};
+    use {SpiModeFlags, SpidevOptions, SpidevTransfer};
+
+    /// An in-memory device that records the ioctls issued to it
+    ///
+    /// As with the kernel, `lsb_first` is stored as a bit of the mode.
+    #[derive(Default)]
+    pub struct MockDevice {
+        pub mode: Cell<u32>,
+        pub bits_per_word: Cell<u8>,
+        pub max_speed_hz: Cell<u32>,
+        /// The highest speed the controller supports, which speeds
+        /// written are clamped to; zero for no limit
+        pub controller_max_hz: Cell<u32>,
+        /// Names of the ioctls issued, in order
+        pub calls: RefCell<Vec<&'static str>>,
+        /// An ioctl that fails with `EINVAL`
+        pub fail: Cell<Option<&'static str>>,
+        /// Clock modes (CPOL/CPHA) that `set_mode` rejects with `EINVAL`
+        pub rejected_modes: RefCell<Vec<SpiModeFlags>>,
+        /// Whether `set_lsb_first` succeeds without changing anything, as
+        /// with a controller that ignores the flag
+        pub ignores_lsb_first: Cell<bool>,
+    }
+
+    impl MockDevice {
+        fn call(&self, name: &'static str) -> io::Result<()> {
+            self.calls.borrow_mut().push(name);
+            if self.fail.get() == Some(name) {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            Ok(())
+        }
+
+        /// The ioctls issued that change the device configuration
+        pub fn writes(&self) -> Vec<&'static str> {
+            self.calls.borrow().iter().cloned().filter(|call| call.starts_with("set_")).collect()
+        }
+    }
+
+    impl Control for MockDevice {
+        fn mode(&self) -> io::Result<SpiModeFlags> {
+            self.call("get_mode")?;
+            Ok(SpiModeFlags::from_bits_truncate(self.mode.get()))
+        }
+
+        fn set_mode(&self, mode: SpiModeFlags) -> io::Result<()> {
+            self.call("set_mode")?;
+            if self.rejected_modes.borrow().contains(&(mode & SpiModeFlags::SPI_MODE_3)) {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            self.mode.set(mode.bits());
+            Ok(())
+        }
+
+        fn mode32(&self) -> io::Result<u32> {
+            self.call("get_mode32")?;
+            Ok(self.mode.get())
+        }
+
+        fn set_mode32(&self, mode: u32) -> io::Result<()> {
+            self.call("set_mode32")?;
+            let clock_bits = SpiModeFlags::from_bits_truncate(mode) & SpiModeFlags::SPI_MODE_3;
+            if self.rejected_modes.borrow().contains(&clock_bits) {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            self.mode.set(mode);
+            Ok(())
+        }
+
+        fn lsb_first(&self) -> io::Result<bool> {
+            self.call("get_lsb_first")?;
+            Ok(self.mode.get() & SpiModeFlags::SPI_LSB_FIRST.bits() != 0)
+        }
+
+        fn set_lsb_first(&self, lsb_first: bool) -> io::Result<()> {
+            self.call("set_lsb_first")?;
+            if self.ignores_lsb_first.get() {
+                return Ok(());
+            }
+            let mut mode = SpiModeFlags::from_bits_truncate(self.mode.get());
+            mode.set(SpiModeFlags::SPI_LSB_FIRST, lsb_first);
+            self.mode.set(mode.bits());
+            Ok(())
+        }
+
+        fn bits_per_word(&self) -> io::Result<u8> {
+            self.call("get_bits_per_word")?;
+            Ok(self.bits_per_word.get())
+        }
+
+        fn set_bits_per_word(&self, bits_per_word: u8) -> io::Result<()> {
+            self.call("set_bits_per_word")?;
+            self.bits_per_word.set(bits_per_word);
+            Ok(())
+        }
+
+        fn max_speed_hz(&self) -> io::Result<u32> {
+            self.call("get_max_speed_hz")?;
+            Ok(self.max_speed_hz.get())
+        }
+
+        fn set_max_speed_hz(&self, max_speed_hz: u32) -> io::Result<()> {
+            self.call("set_max_speed_hz")?;
+            let limit = self.controller_max_hz.get();
+            self.max_speed_hz.set(if limit != 0 && max_speed_hz > limit {
+                limit
+            } else {
+                max_speed_hz
+            });
+            Ok(())
+        }
+
+        fn transfer_multiple(&self, transfers: &mut [SpidevTransfer]) -> io::Result<u32> {
+            self.call("transfer_multiple")?;
+            Ok(transfers.iter().map(|transfer| transfer.tx_slice().len() as u32).sum())
+        }
+    }
+
+    /// A mock configured for 8 bits per word at 1 MHz in mode 0
+    pub fn mock_device() -> MockDevice {
+        let dev = MockDevice::default();
+        dev.bits_per_word.set(8);
+        dev.max_speed_hz.set(1_000_000);
+        dev
+    }
+
+    #[test]
+    fn test_apply_diff_speed_only() {
+        let dev = mock_device();
+        let desired = SpidevOptions::new()
+                          .bits_per_word(8)
+                          .max_speed_hz(500_000)
+                          .lsb_first(false)
+                          .mode(SpiModeFlags::SPI_MODE_0)
+                          .build();
+        let changes = apply_diff_with(&dev, &desired).unwrap();
+        assert_eq!(changes,
+                   ChangeSet {
+                       max_speed_hz: true,
+                       ..Default::default()
+                   });
+        assert_eq!(dev.writes(), ["set_max_speed_hz"]);
+        assert_eq!(dev.max_speed_hz.get(), 500_000);
+    }
+
+    #[test]
+    fn test_apply_diff_unchanged() {
+        let dev = mock_device();
+        let desired = SpidevOptions::new().bits_per_word(8).max_speed_hz(1_000_000).build();
+        assert!(apply_diff_with(&dev, &desired).unwrap().is_empty());
+        assert!(dev.writes().is_empty());
+        // unset options are not even read
+        assert_eq!(*dev.calls.borrow(), ["get_bits_per_word", "get_max_speed_hz"]);
+    }
+
+    #[test]
+    fn test_set_mode_preserves_cs_high() {
+        let dev = mock_device();
+        dev.set_mode(SpiModeFlags::SPI_CS_HIGH | SpiModeFlags::SPI_LSB_FIRST).unwrap();
+        let written = set_mode_preserving_options_with(&dev, SpiModeFlags::SPI_MODE_3).unwrap();
+        assert_eq!(SpiModeFlags::from_bits_truncate(dev.mode.get()),
+                   SpiModeFlags::SPI_CS_HIGH | SpiModeFlags::SPI_LSB_FIRST |
+                   SpiModeFlags::SPI_MODE_3);
+        assert_eq!(written, dev.mode.get());
+
+        // only the clock bits of the argument are used
+        set_mode_preserving_options_with(&dev, SpiModeFlags::SPI_MODE_1 | SpiModeFlags::SPI_3WIRE).unwrap();
+        assert_eq!(SpiModeFlags::from_bits_truncate(dev.mode.get()),
+                   SpiModeFlags::SPI_CS_HIGH | SpiModeFlags::SPI_LSB_FIRST |
+                   SpiModeFlags::SPI_MODE_1);
+    }
+
+    /// `SPI_CS_WORD`, a kernel mode bit `SpiModeFlags` has no flag for
+    const SPI_CS_WORD: u32 = 0x1000;
+
+    #[test]
+    fn test_set_mode_preserves_unknown_bits() {
+        let dev = mock_device();
+        dev.mode.set(SPI_CS_WORD | SpiModeFlags::SPI_MODE_1.bits());
+        set_mode_preserving_options_with(&dev, SpiModeFlags::SPI_MODE_2).unwrap();
+        assert_eq!(dev.mode.get(), SPI_CS_WORD | SpiModeFlags::SPI_MODE_2.bits());
+        assert_eq!(dev.writes(), ["set_mode32"]);
+    }
+
+    #[test]
+    fn test_nearest_achievable_speed() {
+        let dev = mock_device();
+        dev.controller_max_hz.set(8_000_000);
+        assert_eq!(nearest_achievable_speed_with(&dev, 50_000_000).unwrap(), 8_000_000);
+        assert_eq!(nearest_achievable_speed_with(&dev, 2_000_000).unwrap(), 2_000_000);
+        // the prior speed is restored
+        assert_eq!(dev.max_speed_hz.get(), 1_000_000);
+        assert_eq!(dev.writes(), ["set_max_speed_hz"; 4]);
+    }
+
+    #[test]
+    fn test_supported_modes() {
+        let dev = mock_device();
+        dev.mode.set((SpiModeFlags::SPI_CS_HIGH | SpiModeFlags::SPI_MODE_1).bits());
+        dev.rejected_modes.borrow_mut().push(SpiModeFlags::SPI_MODE_2);
+        assert_eq!(supported_modes_with(&dev).unwrap(),
+                   [SpiModeFlags::SPI_MODE_0, SpiModeFlags::SPI_MODE_1, SpiModeFlags::SPI_MODE_3]);
+        // the original mode is restored, even though the last probe
+        // succeeded with a different one
+        assert_eq!(SpiModeFlags::from_bits_truncate(dev.mode.get()),
+                   SpiModeFlags::SPI_CS_HIGH | SpiModeFlags::SPI_MODE_1);
+    }
+
+    #[test]
+    fn test_supported_modes_keeps_undefined_bits() {
+        let dev = mock_device();
+        dev.mode.set(SPI_CS_WORD | SpiModeFlags::SPI_MODE_2.bits());
+        supported_modes_with(&dev).unwrap();
+        assert_eq!(dev.mode.get(), SPI_CS_WORD | SpiModeFlags::SPI_MODE_2.bits());
+    }
+
+    #[test]
+    fn test_apply_diff_mode_and_lsb_first() {
+        let dev = mock_device();
+        let desired = SpidevOptions::new()
+                          .mode(SpiModeFlags::SPI_MODE_3)
+                          .lsb_first(true)
+                          .build();
+        let changes = apply_diff_with(&dev, &desired).unwrap();
+        assert!(changes.spi_mode && changes.lsb_first);
+        // LSB_FIRST is written as part of the mode
+        assert_eq!(dev.writes(), ["set_mode"]);
+        assert_eq!(SpiModeFlags::from_bits_truncate(dev.mode.get()),
+                   SpiModeFlags::SPI_MODE_3 | SpiModeFlags::SPI_LSB_FIRST);
+    }
+
+    #[test]
+    fn test_apply_diff_idempotent() {
+        let dev = mock_device();
+        let desired = SpidevOptions::new()
+                          .bits_per_word(16)
+                          .max_speed_hz(500_000)
+                          .mode(SpiModeFlags::SPI_MODE_0)
+                          .lsb_first(true)
+                          .build();
+        assert!(!apply_diff_with(&dev, &desired).unwrap().is_empty());
+        dev.calls.borrow_mut().clear();
+        // the LSB_FIRST bit in the mode read back must not count as a
+        // difference from the desired mode
+        assert!(apply_diff_with(&dev, &desired).unwrap().is_empty());
+        assert!(dev.writes().is_empty());
+    }
+
+    #[test]
+    fn test_configure_and_transfer() {
+        let dev = mock_device();
+        let options = SpidevOptions::new()
+                          .max_speed_hz(500_000)
+                          .mode(SpiModeFlags::SPI_MODE_0)
+                          .build();
+        let tx_buf = [0; 3];
+        let mut transfers = [SpidevTransfer::write(&tx_buf)];
+        let (bytes, changes) = configure_and_transfer_with(&dev, &options, true, || {
+                                   dev.transfer_multiple(&mut transfers)
+                               })
+                                   .unwrap();
+        assert_eq!(bytes, 3);
+        assert_eq!(changes,
+                   ChangeSet {
+                       max_speed_hz: true,
+                       ..Default::default()
+                   });
+        assert_eq!(*dev.calls.borrow(),
+                   ["get_max_speed_hz",
+                    "get_mode",
+                    "set_max_speed_hz",
+                    "transfer_multiple",
+                    "set_max_speed_hz"]);
+        assert_eq!(dev.max_speed_hz.get(), 1_000_000);
+
+        // when not restoring, the configuration is left as it was set and
+        // a second transfer needs no configuration at all
+        dev.calls.borrow_mut().clear();
+        for _ in 0..2 {
+            configure_and_transfer_with(&dev, &options, false, || dev.transfer_multiple(&mut transfers))
+                .unwrap();
+        }
+        assert_eq!(dev.writes(), ["set_max_speed_hz"]);
+        assert_eq!(dev.max_speed_hz.get(), 500_000);
+    }
+
+    #[test]
+    fn test_configure_and_transfer_restores_on_error() {
+        let dev = mock_device();
+        dev.fail.set(Some("transfer_multiple"));
+        let options = SpidevOptions::new().bits_per_word(16).build();
+        let tx_buf = [0; 4];
+        let mut transfers = [SpidevTransfer::write(&tx_buf)];
+        assert!(configure_and_transfer_with(&dev, &options, true, || dev.transfer_multiple(&mut transfers)).is_err());
+        assert_eq!(dev.writes(), ["set_bits_per_word", "set_bits_per_word"]);
+        assert_eq!(dev.bits_per_word.get(), 8);
+    }
+
+    #[test]
+    fn test_configure_and_transfer_failed_write() {
+        let dev = mock_device();
+        dev.fail.set(Some("set_max_speed_hz"));
+        let options = SpidevOptions::new()
+                          .bits_per_word(16)
+                          .max_speed_hz(500_000)
+                          .build();
+        let tx_buf = [0; 4];
+        let mut transfers = [SpidevTransfer::write(&tx_buf)];
+
+        // the word size written before the failure is restored
+        let err = configure_and_transfer_with(&dev, &options, true, || dev.transfer_multiple(&mut transfers))
+                      .unwrap_err();
+        let failed = err.get_ref().unwrap().downcast_ref::<StepFailed>().unwrap();
+        assert_eq!(failed.setting, Setting::MaxSpeedHz);
+        assert!(failed.applied.bits_per_word);
+        assert_eq!(dev.writes(), ["set_bits_per_word", "set_max_speed_hz", "set_bits_per_word"]);
+        assert_eq!(dev.bits_per_word.get(), 8);
+
+        // and kept, and reported as applied, when not restoring
+        dev.calls.borrow_mut().clear();
+        let err = configure_and_transfer_with(&dev, &options, false, || dev.transfer_multiple(&mut transfers))
+                      .unwrap_err();
+        let failed = err.get_ref().unwrap().downcast_ref::<StepFailed>().unwrap();
+        assert_eq!(failed.applied_options(&options), SpidevOptions::new().bits_per_word(16).build());
+        assert_eq!(dev.writes(), ["set_bits_per_word", "set_max_speed_hz"]);
+        assert_eq!(dev.bits_per_word.get(), 16);
+        assert!(!dev.calls.borrow().contains(&"transfer_multiple"));
+    }
+
+    #[test]
+    fn test_with_mode_restores() {
+        let dev = mock_device();
+        dev.mode.set(SpiModeFlags::SPI_CS_HIGH.bits());
+        let value = with_mode_with(&dev, SpiModeFlags::SPI_MODE_3, || {
+                        assert_eq!(dev.mode.get(), SpiModeFlags::SPI_MODE_3.bits());
+                        Ok(7)
+                    })
+                        .unwrap();
+        assert_eq!(value, 7);
+        assert_eq!(dev.mode.get(), SpiModeFlags::SPI_CS_HIGH.bits());
+
+        let result: io::Result<()> = with_mode_with(&dev, SpiModeFlags::SPI_MODE_1, || {
+            Err(io::Error::from_raw_os_error(libc::EIO))
+        });
+        assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EIO));
+        assert_eq!(dev.mode.get(), SpiModeFlags::SPI_CS_HIGH.bits());
+    }
+
+    #[test]
+    fn test_with_mode_restores_on_panic() {
+        let dev = mock_device();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _: io::Result<()> = with_mode_with(&dev, SpiModeFlags::SPI_MODE_2, || {
+                panic!("transfer failed badly")
+            });
+        }));
+        assert!(result.is_err());
+        assert_eq!(dev.mode.get(), SpiModeFlags::SPI_MODE_0.bits());
+        assert_eq!(dev.writes(), ["set_mode", "set_mode32"]);
+    }
+
+    #[test]
+    fn test_with_mode_keeps_undefined_bits() {
+        let dev = mock_device();
+        dev.mode.set(SPI_CS_WORD | SpiModeFlags::SPI_MODE_1.bits());
+        with_mode_with(&dev, SpiModeFlags::SPI_MODE_3, || Ok(())).unwrap();
+        assert_eq!(dev.mode.get(), SPI_CS_WORD | SpiModeFlags::SPI_MODE_1.bits());
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _: io::Result<()> = with_mode_with(&dev, SpiModeFlags::SPI_MODE_2, || {
+                panic!("transfer failed badly")
+            });
+        }));
+        assert!(result.is_err());
+        assert_eq!(dev.mode.get(), SPI_CS_WORD | SpiModeFlags::SPI_MODE_1.bits());
+    }
+
+    #[test]
+    fn test_supports_lsb_first_honored() {
+        let dev = mock_device();
+        assert!(supports_lsb_first_with(&dev).unwrap());
+        assert_eq!(dev.writes(), ["set_lsb_first", "set_lsb_first"]);
+        assert_eq!(dev.mode.get(), 0);
+    }
+
+    #[test]
+    fn test_supports_lsb_first_ignored() {
+        let dev = mock_device();
+        dev.ignores_lsb_first.set(true);
+        assert!(!supports_lsb_first_with(&dev).unwrap());
+        assert_eq!(dev.mode.get(), 0);
+
+        let dev = mock_device();
+        dev.fail.set(Some("get_lsb_first"));
+        assert!(supports_lsb_first_with(&dev).is_err());
+    }
+
+    #[test]
+    fn test_current_state_after_partial_configure() {
+        let dev = mock_device();
+        dev.fail.set(Some("set_mode"));
+        let options = SpidevOptions::new()
+                          .bits_per_word(16)
+                          .max_speed_hz(500_000)
+                          .mode(SpiModeFlags::SPI_MODE_3)
+                          .lsb_first(true)
+                          .build();
+        let failed = configure_with(&dev, &options).unwrap_err();
+        assert_eq!(failed.setting, Setting::Mode);
+        assert_eq!(failed.applied_options(&options),
+                   SpidevOptions::new()
+                       .bits_per_word(16)
+                       .max_speed_hz(500_000)
+                       .build());
+        assert_eq!(dev.writes(), ["set_bits_per_word", "set_max_speed_hz", "set_mode"]);
+        // the options before the mode were applied, the rest were not
+        assert_eq!(current_state_with(&dev).unwrap(),
+                   SpidevOptions::new()
+                       .bits_per_word(16)
+                       .max_speed_hz(500_000)
+                       .mode(SpiModeFlags::SPI_MODE_0)
+                       .lsb_first(false)
+                       .build());
+    }
+
+    #[test]
+    fn test_commit_issues_needed_writes() {
+        let dev = mock_device();
+        let desired = SpidevOptions::new()
+                          .bits_per_word(16)
+                          .max_speed_hz(500_000)
+                          .mode(SpiModeFlags::SPI_MODE_3)
+                          .lsb_first(false)
+                          .build();
+        let changes = commit_with(&dev, &desired).unwrap();
+        assert_eq!(changes,
+                   ChangeSet {
+                       bits_per_word: true,
+                       max_speed_hz: true,
+                       spi_mode: true,
+                       lsb_first: false,
+                   });
+        assert_eq!(dev.writes(), ["set_bits_per_word", "set_max_speed_hz", "set_mode"]);
+    }
+
+    #[test]
+    fn test_commit_identifies_failed_step() {
+        let dev = mock_device();
+        dev.fail.set(Some("set_mode"));
+        let desired = SpidevOptions::new()
+                          .max_speed_hz(500_000)
+                          .mode(SpiModeFlags::SPI_MODE_3)
+                          .delay_usecs(10)
+                          .build();
+        let err = commit_with(&dev, &desired).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let failed = err.get_ref().unwrap().downcast_ref::<StepFailed>().unwrap();
+        assert_eq!(failed.setting, Setting::Mode);
+        assert_eq!(failed.applied,
+                   ChangeSet {
+                       max_speed_hz: true,
+                       ..Default::default()
+                   });
+        assert_eq!(failed.error.raw_os_error(), Some(libc::EINVAL));
+        assert_eq!(err.to_string(), format!("failed to set mode: {}", failed.error));
+        assert_eq!(failed.applied_options(&desired),
+                   SpidevOptions::new().max_speed_hz(500_000).delay_usecs(10).build());
+    }
+
+    #[test]
+    fn test_switch_profiles() {
+        let dev = mock_device();
+        let adc = DeviceProfile::new(SpidevOptions::new()
+                                         .max_speed_hz(1_000_000)
+                                         .mode(SpiModeFlags::SPI_MODE_0)
+                                         .build());
+        let dac = DeviceProfile::from(SpidevOptions::new()
+                                          .max_speed_hz(20_000_000)
+                                          .mode(SpiModeFlags::SPI_MODE_1)
+                                          .build());
+        // the mock starts out configured as the ADC expects
+        assert!(apply_diff_with(&dev, adc.options()).unwrap().is_empty());
+        assert!(dev.writes().is_empty());
+
+        let changes = apply_diff_with(&dev, dac.options()).unwrap();
+        assert!(changes.max_speed_hz && changes.spi_mode);
+        assert_eq!(dev.max_speed_hz.get(), 20_000_000);
+        assert_eq!(dev.mode.get(), SpiModeFlags::SPI_MODE_1.bits());
+        assert!(apply_diff_with(&dev, dac.options()).unwrap().is_empty());
+
+        dev.calls.borrow_mut().clear();
+        apply_diff_with(&dev, adc.options()).unwrap();
+        assert_eq!(dev.writes(), ["set_max_speed_hz", "set_mode"]);
+        assert_eq!(dev.max_speed_hz.get(), 1_000_000);
+        assert_eq!(dev.mode.get(), SpiModeFlags::SPI_MODE_0.bits());
+    }
+
+    #[test]
+    fn test_verified_writes() {
+        let dev = mock_device();
+        let options = SpidevOptions::new()
+                          .bits_per_word(16)
+                          .max_speed_hz(500_000)
+                          .lsb_first(true)
+                          .build();
+        configure_with(&Verified(&dev), &options).unwrap();
+        assert_eq!(*dev.calls.borrow(),
+                   ["set_bits_per_word",
+                    "get_bits_per_word",
+                    "set_max_speed_hz",
+                    "get_max_speed_hz",
+                    "set_lsb_first",
+                    "get_lsb_first"]);
+    }
+
+    #[test]
+    fn test_verified_write_mismatch() {
+        let dev = mock_device();
+        dev.controller_max_hz.set(10_000_000);
+        let options = SpidevOptions::new().max_speed_hz(50_000_000).build();
+        let err = configure_with(&Verified(&dev), &options).unwrap_err().error;
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(*err.get_ref().unwrap().downcast_ref::<Mismatch>().unwrap(),
+                   Mismatch {
+                       setting: Setting::MaxSpeedHz,
+                       requested: 50_000_000,
+                       actual: 10_000_000,
+                   });
+        assert_eq!(err.to_string(), "max_speed_hz read back as 10000000 after writing 50000000");
+        // without verification the clamp goes unnoticed
+        assert!(configure_with(&dev, &options).is_ok());
+
+        let dev = mock_device();
+        dev.ignores_lsb_first.set(true);
+        let options = SpidevOptions::new().lsb_first(true).build();
+        let err = configure_with(&Verified(&dev), &options).unwrap_err().error;
+        let mismatch = err.get_ref().unwrap().downcast_ref::<Mismatch>().unwrap();
+        assert_eq!((mismatch.setting, mismatch.requested, mismatch.actual),
+                   (Setting::LsbFirst, 1, 0));
+    }
+
+    #[test]
+    fn test_clock_divider_for() {
+        // an exact divider
+        assert_eq!(clock_divider_for(250_000_000, 10_000_000).unwrap(), (25, 10_000_000));
+        // rounded down to the next speed the divider allows
+        assert_eq!(clock_divider_for(250_000_000, 3_000_000).unwrap(), (84, 2_976_190));
+        // faster than the base clock
+        assert_eq!(clock_divider_for(48_000_000, 100_000_000).unwrap(), (1, 48_000_000));
+        assert_eq!(clock_divider_for(48_000_000, 1).unwrap(), (48_000_000, 1));
+        assert_eq!(clock_divider_for(48_000_000, 0).unwrap_err().kind(),
+                   io::ErrorKind::InvalidInput);
+        assert!(clock_divider_for(u64::MAX, 1).is_err());
+    }
+}
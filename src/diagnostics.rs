@@ -0,0 +1,373 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Best-effort helpers for diagnosing devices and wiring
+//!
+//! SPI has no acknowledgement or any other standard way for a device to
+//! signal that it is present, so everything here is a heuristic based on
+//! the data that was clocked in.
+
+use std::fmt;
+use std::io;
+use std::os::unix::prelude::*;
+use std::time::Duration;
+use config::Control;
+use spidevioctl::{self, SpidevTransfer};
+use SpiModeFlags;
+
+/// The number of bytes read by `diagnose_wiring`
+const WIRING_PROBE_LEN: usize = 8;
+
+/// Result of a `probe_present` check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Presence {
+    /// The response contained varying data, so something is driving MISO
+    Present,
+    /// Every byte read back as `0xFF`, which is what a floating MISO
+    /// line with a pull-up looks like
+    Absent,
+    /// The response does not allow a conclusion either way (e.g. it was
+    /// all zeroes, which many idle devices legitimately return)
+    Unknown,
+}
+
+/// Infer device presence from the bytes clocked in during a probe
+pub fn classify_response(rx_buf: &[u8]) -> Presence {
+    if rx_buf.is_empty() || rx_buf.iter().all(|&b| b == 0x00) {
+        Presence::Unknown
+    } else if rx_buf.iter().all(|&b| b == 0xFF) {
+        Presence::Absent
+    } else {
+        Presence::Present
+    }
+}
+
+/// Check whether a device appears to be responding
+///
+/// `tx_buf` is clocked out in a single full-duplex transfer and the
+/// response is classified with `classify_response`.  A command that
+/// makes the device return something recognizable, such as reading an
+/// ID register, gives the most reliable answer; the probe should never
+/// be a command that changes device state.
+///
+/// This is only a heuristic: a device that is present but returns all
+/// `0xFF` will be reported as `Absent`.
+pub fn probe_present(fd: RawFd, tx_buf: &[u8]) -> io::Result<Presence> {
+    probe_present_with(tx_buf, |transfer| spidevioctl::transfer(fd, transfer).map(|_| ()))
+}
+
+/// `probe_present`, performing the transfer with `run`
+pub(crate) fn probe_present_with<F>(tx_buf: &[u8], run: F) -> io::Result<Presence>
+    where F: FnOnce(&mut SpidevTransfer) -> io::Result<()>
+{
+    let mut rx_buf = vec![0; tx_buf.len()];
+    run(&mut SpidevTransfer::read_write(tx_buf, &mut rx_buf))?;
+    Ok(classify_response(&rx_buf))
+}
+
+/// The likely state of the wiring, as judged by `diagnose_wiring`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wiring {
+    /// Varied data was read, so something is driving MISO
+    Plausible,
+    /// Every byte read as `0xFF`: MISO is probably not connected and
+    /// pulled up, or the device is never selected
+    MisoHigh,
+    /// Every byte read as `0x00`: MISO is probably not connected and
+    /// pulled down or shorted to ground, although an idle device may
+    /// legitimately return zeroes
+    MisoLow,
+}
+
+impl fmt::Display for Wiring {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Wiring::Plausible => "MISO is being driven",
+            Wiring::MisoHigh => {
+                "all bytes read as 0xFF: check that MISO is connected and that chip select \
+                 reaches the device with the right polarity"
+            }
+            Wiring::MisoLow => {
+                "all bytes read as 0x00: check that MISO is connected and not shorted to \
+                 ground, unless the device returns zeroes when idle"
+            }
+        })
+    }
+}
+
+/// Infer the likely wiring state from bytes read without a command
+///
+/// An empty response gives no evidence of a fault and is `Plausible`.
+pub fn classify_wiring(rx_buf: &[u8]) -> Wiring {
+    match classify_response(rx_buf) {
+        Presence::Absent => Wiring::MisoHigh,
+        Presence::Unknown if !rx_buf.is_empty() => Wiring::MisoLow,
+        _ => Wiring::Plausible,
+    }
+}
+
+/// Read a few bytes and check whether MISO looks stuck
+///
+/// A handful of bytes is read while shifting out zeroes and classified
+/// with `classify_wiring`.  If the controller's internal loopback
+/// (`SPI_LOOP`) is enabled it is switched off for the read and restored
+/// afterwards, as it would otherwise hide the state of the real line.
+/// Like the other diagnostics this is a hint rather than a verdict.
+pub fn diagnose_wiring(fd: RawFd) -> io::Result<Wiring> {
+    diagnose_wiring_with(&fd, |transfer| spidevioctl::transfer(fd, transfer).map(|_| ()))
+}
+
+/// `diagnose_wiring`, switching the mode through `dev` and performing
+/// the read with `run`
+pub(crate) fn diagnose_wiring_with<C, F>(dev: &C, run: F) -> io::Result<Wiring>
+    where C: Control,
+          F: FnOnce(&mut SpidevTransfer) -> io::Result<()>
+{
+    // the raw mode, so restoring it keeps bits SpiModeFlags doesn't define
+    let mode = dev.mode32()?;
+    let looped = mode & SpiModeFlags::SPI_LOOP.bits() != 0;
+    if looped {
+        dev.set_mode32(mode & !SpiModeFlags::SPI_LOOP.bits())?;
+    }
+    let mut rx_buf = [0; WIRING_PROBE_LEN];
+    let result = run(&mut SpidevTransfer::read(&mut rx_buf));
+    if looped {
+        // restore even if the read failed, but report its error first
+        let restored = dev.set_mode32(mode);
+        result?;
+        restored?;
+    } else {
+        result?;
+    }
+    Ok(classify_wiring(&rx_buf))
+}
+
+/// The result of `check_timing`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimingCheck {
+    /// How long the transfer's ioctl took
+    pub elapsed: Duration,
+    /// How long the transfer should take at least, including its delays
+    pub minimum: Duration,
+}
+
+impl TimingCheck {
+    /// Whether the transfer took at least as long as it should have
+    ///
+    /// A transfer that completed sooner cannot have honored its delays
+    /// (or ran faster than the configured clock).  The converse doesn't
+    /// hold: a transfer that took long enough may still have put its
+    /// delay in the wrong place.
+    pub fn met(&self) -> bool {
+        self.elapsed >= self.minimum
+    }
+}
+
+/// Perform `transfer` and check that it took as long as its timing implies
+///
+/// The minimum is `SpidevTransfer::estimated_duration` using the device's
+/// current word size and speed, unless the transfer overrides them, so
+/// it includes `delay_usecs` and any `word_delay_usecs`.  The elapsed
+/// time covers the whole ioctl, which also includes setup by the kernel
+/// and the controller, so only a shortfall is meaningful.  The chip
+/// select setup and hold delays configured in the device tree are not
+/// visible to userspace and so are not part of the minimum.
+pub fn check_timing(fd: RawFd, transfer: &mut SpidevTransfer) -> io::Result<TimingCheck> {
+    let bits_per_word = spidevioctl::get_bits_per_word(fd)?;
+    let speed_hz = spidevioctl::get_max_speed_hz(fd)?;
+    let minimum = match transfer.estimated_duration(bits_per_word, speed_hz) {
+        Some(minimum) => minimum,
+        None => {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "no speed is configured for the transfer"))
+        }
+    };
+    let (_, elapsed) = spidevioctl::transfer_timed(fd, transfer)?;
+    Ok(TimingCheck { elapsed, minimum })
+}
+
+/// The index of the first byte of `rx_buf` that does not match `pattern`
+pub fn first_mismatch(pattern: &dyn Fn(usize) -> u8, rx_buf: &[u8]) -> Option<usize> {
+    rx_buf.iter().enumerate().position(|(i, &b)| b != pattern(i))
+}
+
+/// Check that data survives a round trip over a loopback connection
+///
+/// A buffer of `len` bytes is generated by calling `pattern` with each
+/// index (a ramp, a PRBS sequence, a constant, ...) and clocked out in a
+/// single full-duplex transfer.  With MOSI looped back to MISO the same
+/// bytes should be received; the index of the first byte that was not is
+/// returned.  Running this at the intended bus speed helps to diagnose
+/// marginal wiring.
+pub fn integrity_test(fd: RawFd, pattern: &dyn Fn(usize) -> u8, len: usize) -> io::Result<Option<usize>> {
+    integrity_test_with(pattern, len, |transfer| {
+        spidevioctl::transfer(fd, transfer).map(|_| ())
+    })
+}
+
+/// `integrity_test`, performing the transfer with `run`
+pub(crate) fn integrity_test_with<F>(pattern: &dyn Fn(usize) -> u8, len: usize, run: F) -> io::Result<Option<usize>>
+    where F: FnOnce(&mut SpidevTransfer) -> io::Result<()>
+{
+    let tx_buf: Vec<u8> = (0..len).map(pattern).collect();
+    let mut rx_buf = vec![0; len];
+    run(&mut SpidevTransfer::read_write(&tx_buf, &mut rx_buf))?;
+    Ok(first_mismatch(pattern, &rx_buf))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use std::io;
+    use libc;
+    use config::test::mock_device;
+    use super::{classify_response, classify_wiring, diagnose_wiring_with, first_mismatch, integrity_test_with,
+                probe_present_with, Presence, TimingCheck, Wiring};
+    use test::test_device;
+    use {SpiModeFlags, SpidevTransfer};
+
+    fn ramp(i: usize) -> u8 {
+        i as u8
+    }
+
+    /// Stand in for a loopback connection, corrupting byte `flip` if given
+    fn loopback(transfer: &mut SpidevTransfer, flip: Option<usize>) -> io::Result<()> {
+        let tx_buf = transfer.tx_slice().to_vec();
+        let rx_buf = transfer.rx_slice_mut();
+        rx_buf.copy_from_slice(&tx_buf);
+        if let Some(i) = flip {
+            rx_buf[i] ^= 0x01;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_classify_all_ones() {
+        assert_eq!(classify_response(&[0xFF; 4]), Presence::Absent);
+    }
+
+    #[test]
+    fn test_classify_all_zeroes() {
+        assert_eq!(classify_response(&[0x00; 4]), Presence::Unknown);
+        assert_eq!(classify_response(&[]), Presence::Unknown);
+    }
+
+    #[test]
+    fn test_classify_varied() {
+        assert_eq!(classify_response(&[0xFF, 0xEF, 0x40, 0x18]), Presence::Present);
+        assert_eq!(classify_response(&[0xA5; 4]), Presence::Present);
+    }
+
+    #[test]
+    fn test_classify_wiring() {
+        assert_eq!(classify_wiring(&[0xFF; 8]), Wiring::MisoHigh);
+        assert_eq!(classify_wiring(&[0x00; 8]), Wiring::MisoLow);
+        assert_eq!(classify_wiring(&[0x00, 0x12, 0xFF, 0x00]), Wiring::Plausible);
+        assert_eq!(classify_wiring(&[]), Wiring::Plausible);
+        assert!(Wiring::MisoHigh.to_string().contains("MISO"));
+    }
+
+    #[test]
+    fn test_first_mismatch_ramp() {
+        let rx_buf: Vec<u8> = (0..300).map(ramp).collect();
+        assert_eq!(first_mismatch(&ramp, &rx_buf), None);
+    }
+
+    #[test]
+    fn test_first_mismatch_injected() {
+        let mut rx_buf: Vec<u8> = (0..300).map(ramp).collect();
+        rx_buf[257] ^= 0x10;
+        rx_buf[290] ^= 0x01;
+        assert_eq!(first_mismatch(&ramp, &rx_buf), Some(257));
+        assert_eq!(first_mismatch(&|_| 0x5a, &[0x5a, 0x5a, 0xff]), Some(2));
+    }
+
+    #[test]
+    fn test_probe_present_with() {
+        let response = |byte| {
+            move |transfer: &mut SpidevTransfer| {
+                for b in transfer.rx_slice_mut() {
+                    *b = byte;
+                }
+                Ok(())
+            }
+        };
+        assert_eq!(probe_present_with(&[0x9f, 0, 0], response(0xFF)).unwrap(), Presence::Absent);
+        assert_eq!(probe_present_with(&[0x9f, 0, 0], response(0x00)).unwrap(), Presence::Unknown);
+        assert_eq!(probe_present_with(&[0x9f, 0, 0], |t| loopback(t, None)).unwrap(),
+                   Presence::Present);
+    }
+
+    #[test]
+    fn test_integrity_test_with() {
+        assert_eq!(integrity_test_with(&ramp, 300, |t| loopback(t, None)).unwrap(), None);
+        assert_eq!(integrity_test_with(&ramp, 300, |t| loopback(t, Some(257))).unwrap(), Some(257));
+    }
+
+    #[test]
+    fn test_diagnose_wiring_restores_mode() {
+        // SPI_LOOP with SPI_CS_WORD, which SpiModeFlags has no flag for
+        let mode = SpiModeFlags::SPI_LOOP.bits() | 0x1000;
+        let dev = mock_device();
+        dev.mode.set(mode);
+        let wiring = diagnose_wiring_with(&dev, |transfer| {
+                         assert_eq!(dev.mode.get(), 0x1000);
+                         for byte in transfer.rx_slice_mut() {
+                             *byte = 0xFF;
+                         }
+                         Ok(())
+                     })
+                         .unwrap();
+        assert_eq!(wiring, Wiring::MisoHigh);
+        assert_eq!(dev.mode.get(), mode);
+
+        // restored when the read fails too
+        let err = diagnose_wiring_with(&dev, |_| Err(io::Error::from_raw_os_error(libc::EIO)))
+                      .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        assert_eq!(dev.mode.get(), mode);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_diagnose_wiring_device() {
+        println!("{}", test_device().diagnose_wiring().unwrap());
+    }
+
+    #[test]
+    fn test_timing_check_met() {
+        let check = |elapsed, minimum| {
+            TimingCheck {
+                elapsed: Duration::from_micros(elapsed),
+                minimum: Duration::from_micros(minimum),
+            }
+        };
+        assert!(check(1200, 1000).met());
+        assert!(check(1000, 1000).met());
+        assert!(!check(400, 1000).met());
+    }
+
+    #[test]
+    #[ignore]
+    fn test_check_timing_device() {
+        let spidev = test_device();
+        let tx_buf = [0; 4];
+        let mut transfer = SpidevTransfer::write(&tx_buf).delay_usecs(2000u16);
+        let check = spidev.check_timing(&mut transfer).unwrap();
+        assert!(check.minimum >= Duration::from_millis(2));
+        assert!(check.met(), "{:?}", check);
+        // allow plenty of scheduling slack
+        assert!(check.elapsed < check.minimum + Duration::from_millis(100));
+    }
+
+    #[test]
+    #[ignore]
+    fn test_integrity_test_loopback() {
+        assert_eq!(test_device().integrity_test(&ramp, 256).unwrap(), None);
+    }
+}
@@ -0,0 +1,181 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Periodic self-tests on a background thread
+//!
+//! `HealthMonitor` lets a long-running service notice that its bus has
+//! stopped responding, e.g. so that a supervisor can restart it, without
+//! the service having to interleave checks with its own work.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use Spidev;
+
+/// The number of bytes clocked by the check used by `loopback`
+const LOOPBACK_CHECK_LEN: usize = 16;
+
+/// Runs a check periodically and keeps the last result
+///
+/// The first check runs as soon as the monitor starts and then once per
+/// interval.  The monitor is stopped when dropped, waiting for a check in
+/// progress to finish.
+pub struct HealthMonitor {
+    healthy: Arc<AtomicBool>,
+    checks: Arc<AtomicUsize>,
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl HealthMonitor {
+    /// Monitor a device with MOSI looped back to MISO
+    ///
+    /// Each check is an `integrity_test` of a short ramp, so the bus is
+    /// healthy while the ramp survives the round trip.  The device is
+    /// moved to the background thread, and is closed when the monitor is
+    /// dropped.
+    pub fn loopback(spidev: Spidev, interval: Duration) -> io::Result<HealthMonitor> {
+        HealthMonitor::with_check(interval, move || {
+            Ok(spidev.integrity_test(&|i| i as u8, LOOPBACK_CHECK_LEN)?.is_none())
+        })
+    }
+
+    /// Start a monitor that runs `check` every `interval`
+    ///
+    /// `check` should be lightweight and leave the device as it found
+    /// it, such as reading an ID register and comparing it with the
+    /// expected value.  The bus is considered healthy while it returns
+    /// `Ok(true)`; an error counts as unhealthy.
+    pub fn with_check<F>(interval: Duration, mut check: F) -> io::Result<HealthMonitor>
+        where F: FnMut() -> io::Result<bool> + Send + 'static
+    {
+        let healthy = Arc::new(AtomicBool::new(false));
+        let checks = Arc::new(AtomicUsize::new(0));
+        let (stop, stop_rx) = mpsc::channel();
+        let thread = {
+            let healthy = healthy.clone();
+            let checks = checks.clone();
+            thread::Builder::new().name("spidev-health".to_string()).spawn(move || {
+                loop {
+                    healthy.store(check().unwrap_or(false), Ordering::SeqCst);
+                    checks.fetch_add(1, Ordering::SeqCst);
+                    // nothing is ever sent, so this returns when the
+                    // monitor drops the sender
+                    match stop_rx.recv_timeout(interval) {
+                        Err(RecvTimeoutError::Timeout) => {}
+                        _ => break,
+                    }
+                }
+            })?
+        };
+        Ok(HealthMonitor {
+            healthy,
+            checks,
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    /// The result of the most recent check
+    ///
+    /// This is `false` until the first check has passed; see `checks`.
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::SeqCst)
+    }
+
+    /// The number of checks completed so far
+    pub fn checks(&self) -> usize {
+        self.checks.load(Ordering::SeqCst)
+    }
+
+    /// Stop the monitor, waiting for a check in progress to finish
+    ///
+    /// This is the same as dropping it.
+    pub fn stop(self) {}
+}
+
+impl Drop for HealthMonitor {
+    fn drop(&mut self) {
+        // dropping the sender wakes the background thread between checks
+        self.stop = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use libc;
+    use super::HealthMonitor;
+    use test::test_device;
+
+    /// Wait for the monitor to have completed `count` checks in all
+    fn wait_for_checks(monitor: &HealthMonitor, count: usize) {
+        let start = Instant::now();
+        while monitor.checks() < count {
+            assert!(start.elapsed() < Duration::from_secs(5), "monitor stopped checking");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_monitor_health() {
+        let failing = Arc::new(AtomicBool::new(false));
+        let monitor = {
+            let failing = failing.clone();
+            HealthMonitor::with_check(Duration::from_millis(1), move || {
+                    if failing.load(Ordering::SeqCst) {
+                        Err(io::Error::from_raw_os_error(libc::EIO))
+                    } else {
+                        Ok(true)
+                    }
+                })
+                .unwrap()
+        };
+        wait_for_checks(&monitor, 1);
+        assert!(monitor.is_healthy());
+
+        failing.store(true, Ordering::SeqCst);
+        // the check in progress may have read the flag before it was set
+        wait_for_checks(&monitor, monitor.checks() + 2);
+        assert!(!monitor.is_healthy());
+
+        failing.store(false, Ordering::SeqCst);
+        wait_for_checks(&monitor, monitor.checks() + 2);
+        assert!(monitor.is_healthy());
+        monitor.stop();
+    }
+
+    #[test]
+    fn test_monitor_stops_promptly() {
+        let monitor = HealthMonitor::with_check(Duration::from_secs(3600), || Ok(false)).unwrap();
+        wait_for_checks(&monitor, 1);
+        assert!(!monitor.is_healthy());
+        let start = Instant::now();
+        monitor.stop();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    #[ignore]
+    fn test_monitor_loopback_device() {
+        let monitor = HealthMonitor::loopback(test_device(), Duration::from_millis(10)).unwrap();
+        wait_for_checks(&monitor, 1);
+        // requires MOSI to be looped back to MISO
+        assert!(monitor.is_healthy());
+    }
+}
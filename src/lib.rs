@@ -28,21 +28,21 @@
 //! use spidev::{Spidev, SpidevOptions, SpidevTransfer, SpiModeFlags};
 //!
 //! fn create_spi() -> io::Result<Spidev> {
-//!     let mut spi = try!(Spidev::open("/dev/spidev0.0"));
+//!     let mut spi = Spidev::open("/dev/spidev0.0")?;
 //!     let options = SpidevOptions::new()
 //!          .bits_per_word(8)
 //!          .max_speed_hz(20_000)
 //!          .mode(SpiModeFlags::SPI_MODE_0)
 //!          .build();
-//!     try!(spi.configure(&options));
+//!     spi.configure(&options)?;
 //!     Ok(spi)
 //! }
 //!
 //! /// perform half duplex operations using Read and Write traits
 //! fn half_duplex(spi: &mut Spidev) -> io::Result<()> {
 //!     let mut rx_buf = [0_u8; 10];
-//!     try!(spi.write(&[0x01, 0x02, 0x03]));
-//!     try!(spi.read(&mut rx_buf));
+//!     spi.write(&[0x01, 0x02, 0x03])?;
+//!     spi.read(&mut rx_buf)?;
 //!     println!("{:?}", rx_buf);
 //!     Ok(())
 //! }
@@ -55,7 +55,7 @@
 //!     let mut rx_buf = [0; 3];
 //!     {
 //!         let mut transfer = SpidevTransfer::read_write(&tx_buf, &mut rx_buf);
-//!         try!(spi.transfer(&mut transfer));
+//!         spi.transfer(&mut transfer)?;
 //!     }
 //!     println!("{:?}", rx_buf);
 //!     Ok(())
@@ -73,14 +73,44 @@ extern crate libc;
 extern crate nix;
 #[macro_use]
 extern crate bitflags;
+#[cfg(feature = "bytes")]
+extern crate bytes;
 
 pub mod spidevioctl;
-pub use spidevioctl::SpidevTransfer;
+pub mod buffer;
+pub mod chunked;
+pub mod config;
+pub mod diagnostics;
+pub mod health;
+#[macro_use]
+pub mod message;
+#[cfg(feature = "mlock")]
+pub mod pinned;
+pub mod ready;
+pub mod recording;
+pub mod stream;
+pub mod sysfs;
+pub mod words;
+pub use buffer::AlignedBuffer;
+pub use config::{ChangeSet, DeviceProfile};
+pub use spidevioctl::{SpidevTransfer, InvalidTransfer, Microseconds, WordDelay, estimated_duration};
+pub use diagnostics::{Presence, TimingCheck, Wiring};
+pub use health::HealthMonitor;
+pub use message::Message;
+#[cfg(feature = "mlock")]
+pub use pinned::PinnedBuffer;
+pub use recording::RecordingSpidev;
+pub use stream::{StreamBuffer, StreamingReader};
+pub use words::{ByteOrder, WordSize, WordsU16, WordsU32};
+pub use sysfs::{ControllerInfo, Statistics};
 
+use std::convert::TryFrom;
+use std::fmt;
 use std::io;
 use std::io::prelude::*;
 use std::fs::{File, OpenOptions};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use std::os::unix::prelude::*;
 
 // Constants extracted from linux/spi/spidev.h
@@ -119,12 +149,56 @@ bitflags! {
         const SPI_RX_DUAL = 0x400;
         /// Receive with 4 wires
         const SPI_RX_QUAD = 0x800;
+        /// Transmit with 8 wires, for controllers that support octal I/O
+        const SPI_TX_OCTAL = 0x2000;
+        /// Receive with 8 wires, for controllers that support octal I/O
+        const SPI_RX_OCTAL = 0x4000;
+    }
+}
+
+impl SpiModeFlags {
+    /// Parse 32-bit mode bits, such as those read with `SPI_IOC_RD_MODE32`
+    ///
+    /// Bits that do not correspond to a known flag are rejected with an
+    /// error of kind `InvalidData` rather than silently dropped, so the
+    /// result always converts back to exactly `bits`.
+    pub fn from_mode_bits(bits: u32) -> io::Result<SpiModeFlags> {
+        match SpiModeFlags::from_bits(bits) {
+            Some(flags) => Ok(flags),
+            None => {
+                Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("unknown SPI mode bits {:#x}",
+                                           bits & !SpiModeFlags::all().bits())))
+            }
+        }
+    }
+
+    /// Parse an 8-bit mode byte, such as one read with `SPI_IOC_RD_MODE`
+    ///
+    /// Every bit of the byte is a known flag, so this cannot fail.
+    pub fn from_mode_byte(bits: u8) -> SpiModeFlags {
+        SpiModeFlags::from_bits_truncate(u32::from(bits))
+    }
+
+    /// Wrap 32-bit mode bits, keeping any that no flag is defined for
+    ///
+    /// Unlike `from_bits_truncate` the result writes back exactly, so a
+    /// mode read from the kernel can be kept and reapplied later.
+    pub(crate) fn from_raw(bits: u32) -> SpiModeFlags {
+        SpiModeFlags { bits }
     }
 }
 
 /// Provide high-level access to Linux Spidev Driver
 pub struct Spidev {
     devfile: File,
+    path: PathBuf,
+    options: SpidevOptions,
+    active_speed_hz: Option<u32>,
+    delay_usecs: Option<u16>,
+    session_bits_per_word: Option<u8>,
+    verify_writes: bool,
+    slave: bool,
 }
 
 /// Options that control defaults for communication on a device
@@ -136,12 +210,13 @@ pub struct Spidev {
 /// Options that are not configured with one of the builder
 /// functions will not be modified in the kernel when
 /// `configure` is called.
-#[derive(Clone)]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct SpidevOptions {
     pub bits_per_word: Option<u8>,
     pub max_speed_hz: Option<u32>,
     pub lsb_first: Option<bool>,
     pub spi_mode: Option<SpiModeFlags>,
+    pub delay_usecs: Option<u16>,
 }
 
 impl SpidevOptions {
@@ -152,6 +227,7 @@ impl SpidevOptions {
             max_speed_hz: None,
             lsb_first: None,
             spi_mode: None,
+            delay_usecs: None,
         }
     }
 
@@ -163,6 +239,12 @@ impl SpidevOptions {
         self
     }
 
+    /// The number of bits in each SPI transfer word, as a `WordSize`
+    pub fn word_size(&mut self, size: WordSize) -> &mut Self {
+        self.bits_per_word = Some(size.bits());
+        self
+    }
+
     /// The maximum SPI transfer speed, in Hz
     ///
     /// The controller can't necessarily assign that specific clock speed.
@@ -199,10 +281,39 @@ impl SpidevOptions {
         self
     }
 
+    /// The delay after each transfer, in microseconds
+    ///
+    /// Unlike the other options this is not a kernel setting: it is kept
+    /// by the `Spidev` and used for every transfer performed through it
+    /// whose own `delay_usecs` is zero.
+    pub fn delay_usecs(&mut self, delay_usecs: u16) -> &mut Self {
+        self.delay_usecs = Some(delay_usecs);
+        self
+    }
+
     /// Finalize and build the SpidevOptions
     pub fn build(&self) -> Self {
         self.clone()
     }
+
+    /// Overwrite these options with any that are set in `other`
+    fn merge(&mut self, other: &SpidevOptions) {
+        if other.bits_per_word.is_some() {
+            self.bits_per_word = other.bits_per_word;
+        }
+        if other.max_speed_hz.is_some() {
+            self.max_speed_hz = other.max_speed_hz;
+        }
+        if other.lsb_first.is_some() {
+            self.lsb_first = other.lsb_first;
+        }
+        if other.spi_mode.is_some() {
+            self.spi_mode = other.spi_mode;
+        }
+        if other.delay_usecs.is_some() {
+            self.delay_usecs = other.delay_usecs;
+        }
+    }
 }
 
 impl Spidev {
@@ -212,38 +323,553 @@ impl Spidev {
     /// where the first number if the bus and the second number
     /// is the chip select on that bus for the device being targeted.
     pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Spidev> {
-        let devfile = try!(OpenOptions::new()
-                               .read(true)
-                               .write(true)
-                               .create(false)
-                               .open(path));
-        Ok(Spidev { devfile: devfile })
+        let path = path.as_ref();
+        Ok(Spidev {
+            devfile: Spidev::open_file(path)?,
+            path: path.to_path_buf(),
+            options: SpidevOptions::new(),
+            active_speed_hz: None,
+            delay_usecs: None,
+            session_bits_per_word: None,
+            verify_writes: false,
+            slave: false,
+        })
+    }
+
+    fn open_file(path: &Path) -> io::Result<File> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(false)
+            .open(path)
+    }
+
+    /// Close and reopen the device, then reapply its configuration
+    ///
+    /// All the options configured through this handle are written again
+    /// to the new file descriptor; settings changed only temporarily, as
+    /// by `with_mode` or the probes that restore the previous value, are
+    /// not.  This is a common way to recover after a transfer fails with
+    /// a serious error such as `EIO`, particularly if the device node was
+    /// recreated by the controller driver being rebound.  If the device
+    /// can't be reopened the handle is left using the old file
+    /// descriptor.
+    pub fn reopen(&mut self) -> io::Result<()> {
+        self.devfile = Spidev::open_file(&self.path)?;
+        let options = self.options.clone();
+        self.configure(&options)
+    }
+
+    /// Open a spidev device attached to a controller in slave mode
+    ///
+    /// This requires a kernel built with `CONFIG_SPI_SLAVE` (4.13+), a
+    /// controller driver with slave support configured as a slave
+    /// (typically with the `spi-slave` devicetree property), and spidev
+    /// instantiated on it, e.g. with
+    /// `echo spidev > /sys/class/spi_slave/spi0/slave`.
+    ///
+    /// The controller is checked through sysfs; an error with kind
+    /// `InvalidInput` is returned if it is a master.  With a slave
+    /// controller the remote master drives the clock and chip select, so
+    /// a transfer blocks until the master clocks the data and the speed,
+    /// `cs_change` and delay settings have no effect on timing.  Most
+    /// slave drivers abort a pending transfer when the process receives a
+    /// signal, in which case it fails with `Interrupted`.
+    pub fn open_slave<P: AsRef<Path>>(path: P) -> io::Result<Spidev> {
+        let mut spidev = Spidev::open(path)?;
+        if !spidev.controller_info()?.slave {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "device is not attached to a slave controller"));
+        }
+        spidev.slave = true;
+        Ok(spidev)
+    }
+
+    /// Whether this handle was opened with `open_slave`
+    pub fn is_slave(&self) -> bool {
+        self.slave
+    }
+
+    /// Open the spidev device with the provided path and configure it
+    ///
+    /// This combines `open` and `configure`.  If the configuration
+    /// cannot be applied the device is closed again before the error is
+    /// returned.
+    pub fn open_with_options<P: AsRef<Path>>(path: P, options: &SpidevOptions) -> io::Result<Spidev> {
+        let mut spidev = Spidev::open(path)?;
+        spidev.configure(options)?;
+        Ok(spidev)
     }
 
     /// Write the provided configuration to this device
+    ///
+    /// The options are written one at a time, so if one fails those
+    /// before it have already been applied; `current_state` shows where
+    /// that left the device.
     pub fn configure(&mut self, options: &SpidevOptions) -> io::Result<()> {
         // write out each present option to the device.  Options
         // that are None are left as-is, in order to reduce
         // overhead
         let fd = self.devfile.as_raw_fd();
-        if let Some(bpw) = options.bits_per_word {
-            try!(spidevioctl::set_bits_per_word(fd, bpw));
+        let result = if self.verify_writes {
+            config::configure_with(&config::Verified(&fd), options)
+        } else {
+            config::configure_with(&fd, options)
+        };
+        match result {
+            Ok(_) => {
+                self.record_applied(options);
+                Ok(())
+            }
+            Err(failed) => {
+                // the settings written before the failure remain applied
+                self.record_applied(&failed.applied_options(options));
+                Err(failed.error)
+            }
+        }
+    }
+
+    /// Change only the clock polarity and phase, preserving option bits
+    ///
+    /// `mode` should be one of `SPI_MODE_0` through `SPI_MODE_3`; any
+    /// other bits in it are ignored.  Options such as `SPI_CS_HIGH`
+    /// that share the mode byte with CPOL/CPHA are left as they are,
+    /// whereas `SpidevOptions::mode` replaces the whole mode.  The
+    /// resulting mode is kept with the options reapplied by `reopen`.
+    pub fn set_mode_preserving_options(&mut self, mode: SpiModeFlags) -> io::Result<()> {
+        let fd = self.devfile.as_raw_fd();
+        let mode = if self.verify_writes {
+            config::set_mode_preserving_options_with(&config::Verified(&fd), mode)?
+        } else {
+            config::set_mode_preserving_options_with(&fd, mode)?
+        };
+        self.record_applied(&SpidevOptions::new().mode(SpiModeFlags::from_raw(mode)).build());
+        Ok(())
+    }
+
+    /// Apply only those options that differ from the device's current state
+    ///
+    /// Returns which settings were actually written; see
+    /// `config::apply_diff`.
+    pub fn apply_diff(&mut self, desired: &SpidevOptions) -> io::Result<ChangeSet> {
+        let fd = self.devfile.as_raw_fd();
+        let changes = if self.verify_writes {
+            config::apply_diff_with(&config::Verified(&fd), desired)?
+        } else {
+            config::apply_diff(fd, desired)?
+        };
+        self.record_applied(desired);
+        Ok(changes)
+    }
+
+    /// Switch to the settings of one of several logical devices sharing
+    /// this device
+    ///
+    /// Only the settings that differ are written, as with `apply_diff`;
+    /// see `config::DeviceProfile`.
+    pub fn activate(&mut self, profile: &DeviceProfile) -> io::Result<ChangeSet> {
+        self.apply_diff(profile.options())
+    }
+
+    /// Start a batch of configuration changes, applied together by
+    /// `ConfigTransaction::commit`
+    ///
+    /// ```no_run
+    /// # use spidev::{Spidev, SpiModeFlags};
+    /// let mut spidev = Spidev::open("/dev/spidev0.0").unwrap();
+    /// spidev.config()
+    ///       .bits_per_word(8)
+    ///       .max_speed_hz(1_000_000)
+    ///       .mode(SpiModeFlags::SPI_MODE_3)
+    ///       .commit()
+    ///       .unwrap();
+    /// ```
+    pub fn config(&mut self) -> ConfigTransaction<'_> {
+        ConfigTransaction {
+            spidev: self,
+            options: SpidevOptions::new(),
         }
-        if let Some(speed) = options.max_speed_hz {
-            try!(spidevioctl::set_max_speed_hz(fd, speed));
+    }
+
+    /// Update the per-handle state after `options` have been applied
+    fn record_applied(&mut self, options: &SpidevOptions) {
+        if options.max_speed_hz.is_some() {
+            self.active_speed_hz = options.max_speed_hz;
         }
-        if let Some(lsb_first) = options.lsb_first {
-            try!(spidevioctl::set_lsb_first(fd, lsb_first));
+        if options.delay_usecs.is_some() {
+            self.delay_usecs = options.delay_usecs;
         }
-        if let Some(spi_mode_flags) = options.spi_mode {
-            try!(spidevioctl::set_mode(fd, spi_mode_flags));
+        self.options.merge(options);
+    }
+
+    /// Apply the options that differ from the device's state, then
+    /// perform `transfer`
+    ///
+    /// With `restore_after` the settings that were changed are written
+    /// back afterwards; otherwise they are kept, as by `apply_diff`.  See
+    /// `config::configure_and_transfer`.
+    pub fn configure_and_transfer(&mut self,
+                                  options: &SpidevOptions,
+                                  transfer: &mut SpidevTransfer,
+                                  restore_after: bool)
+                                  -> io::Result<()> {
+        let fd = self.devfile.as_raw_fd();
+        if self.verify_writes {
+            self.configure_and_transfer_on(&config::Verified(&fd), options, transfer, restore_after)
+        } else {
+            self.configure_and_transfer_on(&fd, options, transfer, restore_after)
+        }
+    }
+
+    /// `configure_and_transfer`, writing the options through `dev`
+    ///
+    /// The options are recorded before the transfer is prepared, so it
+    /// sees their word size and delay, and are kept recorded even if the
+    /// transfer fails.  If writing an option fails, only those written
+    /// before it are recorded.  With `restore_after` the handle's
+    /// previous state is put back along with the device's.
+    fn configure_and_transfer_on<C: config::Control>(&mut self,
+                                                     dev: &C,
+                                                     options: &SpidevOptions,
+                                                     transfer: &mut SpidevTransfer,
+                                                     restore_after: bool)
+                                                     -> io::Result<()> {
+        let saved = (self.options.clone(), self.active_speed_hz, self.delay_usecs);
+        let result = config::configure_and_transfer_with(dev, options, restore_after, || {
+            self.record_applied(options);
+            self.transfer(transfer)
+        });
+        if restore_after {
+            let (options, active_speed_hz, delay_usecs) = saved;
+            self.options = options;
+            self.active_speed_hz = active_speed_hz;
+            self.delay_usecs = delay_usecs;
+        } else if let Err(ref err) = result {
+            // the settings written before a failed write remain applied
+            let failed = err.get_ref().and_then(|err| err.downcast_ref::<config::StepFailed>());
+            if let Some(failed) = failed {
+                self.record_applied(&failed.applied_options(options));
+            }
+        }
+        result.map(|_| ())
+    }
+
+    /// Read the device's whole configuration from the kernel
+    ///
+    /// See `config::current_state`.
+    pub fn current_state(&self) -> io::Result<SpidevOptions> {
+        config::current_state(self.devfile.as_raw_fd())
+    }
+
+    /// The maximum SPI transfer speed last configured through this handle
+    ///
+    /// This is cached when `configure`, `apply_diff` or
+    /// `set_max_speed_hz` sets the speed, so reading it does not require
+    /// a round-trip to the kernel.  `None` is returned if the speed has
+    /// not been configured via this handle; use `max_speed_hz` to query
+    /// the kernel directly.  Setting the speed on the file descriptor
+    /// with `spidevioctl::set_max_speed_hz` bypasses the handle and
+    /// leaves this value stale.
+    pub fn active_speed_hz(&self) -> Option<u32> {
+        self.active_speed_hz
+    }
+
+    /// Write the maximum SPI transfer speed, in Hz, to the kernel
+    ///
+    /// This is `configure` with only `max_speed_hz` set, keeping
+    /// `active_speed_hz` in sync.
+    pub fn set_max_speed_hz(&mut self, max_speed_hz: u32) -> io::Result<()> {
+        self.configure(&SpidevOptions::new().max_speed_hz(max_speed_hz).build())
+    }
+
+    /// Read the maximum SPI transfer speed, in Hz, from the kernel
+    pub fn max_speed_hz(&self) -> io::Result<u32> {
+        spidevioctl::get_max_speed_hz(self.devfile.as_raw_fd())
+    }
+
+    /// The delay used for transfers that don't specify their own
+    ///
+    /// See `SpidevOptions::delay_usecs`.
+    pub fn default_delay_usecs(&self) -> Option<u16> {
+        self.delay_usecs
+    }
+
+    /// Use `bits_per_word` for transfers that don't specify their own
+    ///
+    /// Unlike `SpidevOptions::bits_per_word` this doesn't change the
+    /// kernel's default for the device: the value is kept by this handle
+    /// and filled in to each transfer performed through it whose own
+    /// `bits_per_word` is zero.  This suits drivers that mostly use one
+    /// word size but occasionally need another, while other users of the
+    /// device keep the kernel default.  Zero clears the session default.
+    pub fn set_session_bits_per_word(&mut self, bits_per_word: u8) {
+        self.session_bits_per_word = match bits_per_word {
+            0 => None,
+            bits_per_word => Some(bits_per_word),
+        };
+    }
+
+    /// The word size set with `set_session_bits_per_word`, if any
+    pub fn session_bits_per_word(&self) -> Option<u8> {
+        self.session_bits_per_word
+    }
+
+    /// Read back each setting written by `configure`, `apply_diff`,
+    /// `activate` and `ConfigTransaction::commit`
+    ///
+    /// With this enabled a setting that reads back differently from the
+    /// value written fails with a `config::Mismatch`; see
+    /// `config::set_and_verify`.  It is off by default, as it doubles
+    /// the number of ioctls needed to configure the device.
+    pub fn set_verify_writes(&mut self, verify: bool) {
+        self.verify_writes = verify;
+    }
+
+    /// Whether writes are verified; see `set_verify_writes`
+    pub fn verify_writes(&self) -> bool {
+        self.verify_writes
+    }
+
+    /// Fill in the session defaults for any transfer that doesn't
+    /// override them, then check the transfers
+    ///
+    /// Transfers still without their own `bits_per_word` are checked
+    /// against the word size configured through this handle, if any.
+    pub(crate) fn prepare(&self, transfers: &mut [SpidevTransfer]) -> io::Result<()> {
+        for transfer in transfers.iter_mut() {
+            if let Some(delay_usecs) = self.delay_usecs {
+                if transfer.delay_usecs == 0 {
+                    transfer.delay_usecs = delay_usecs;
+                }
+            }
+            if let Some(bits_per_word) = self.session_bits_per_word {
+                if transfer.bits_per_word == 0 {
+                    transfer.bits_per_word = bits_per_word;
+                }
+            }
         }
+        spidevioctl::validate(transfers, self.options.bits_per_word.unwrap_or(0))?;
         Ok(())
     }
 
+    /// Query the kernel for the configured word size
+    pub fn word_size(&self) -> io::Result<WordSize> {
+        WordSize::try_from(spidevioctl::get_bits_per_word(self.devfile.as_raw_fd())?)
+    }
+
+    /// List the clock modes the controller accepts for this device
+    ///
+    /// The current mode is restored afterwards; see
+    /// `config::supported_modes`.
+    pub fn supported_modes(&self) -> io::Result<Vec<SpiModeFlags>> {
+        config::supported_modes(self.devfile.as_raw_fd())
+    }
+
+    /// Compute the clock divider and resulting speed for `requested_hz`
+    /// as `(divider, actual_hz)`
+    ///
+    /// This needs the controller's base clock from sysfs; see
+    /// `config::clock_divider`.
+    pub fn clock_divider(&self, requested_hz: u32) -> io::Result<(u32, u32)> {
+        config::clock_divider(self.devfile.as_raw_fd(), requested_hz)
+    }
+
+    /// Check whether the controller honors LSB-first bit order
+    ///
+    /// See `config::supports_lsb_first`.
+    pub fn supports_lsb_first(&self) -> io::Result<bool> {
+        config::supports_lsb_first(self.devfile.as_raw_fd())
+    }
+
+    /// Find the speed the kernel settles on for `requested_hz`
+    ///
+    /// The current speed is restored afterwards; see
+    /// `config::nearest_achievable_speed`.
+    pub fn nearest_achievable_speed(&self, requested_hz: u32) -> io::Result<u32> {
+        config::nearest_achievable_speed(self.devfile.as_raw_fd(), requested_hz)
+    }
+
     /// Perform a single transfer
     pub fn transfer(&self, transfer: &mut SpidevTransfer) -> io::Result<()> {
-        spidevioctl::transfer(self.devfile.as_raw_fd(), transfer)
+        self.prepare(std::slice::from_mut(transfer))?;
+        spidevioctl::transfer(self.devfile.as_raw_fd(), transfer)?;
+        Ok(())
+    }
+
+    /// Perform a single transfer without holding a caller's lock
+    ///
+    /// This is for applications that guard a shared bus with their own
+    /// lock but don't want to hold it for the duration of the ioctl.  The
+    /// transfer is prepared and validated, then `guard` is dropped to
+    /// release the lock, the transfer is performed, and `reacquire` is
+    /// called to take the lock again.  The new guard is returned along
+    /// with the result of the transfer, which is reacquired whether or
+    /// not the transfer succeeded, as with `Condvar::wait`.
+    ///
+    /// ```no_run
+    /// # use std::sync::Mutex;
+    /// # use spidev::{Spidev, SpidevTransfer};
+    /// # let spidev = Spidev::open("/dev/spidev0.0").unwrap();
+    /// let state = Mutex::new(0u32);
+    /// let guard = state.lock().unwrap();
+    /// let tx_buf = [0x01, 0x02];
+    /// let mut transfer = SpidevTransfer::write(&tx_buf);
+    /// let (guard, result) = spidev.transfer_unlocked(guard, &mut transfer, || state.lock().unwrap());
+    /// ```
+    ///
+    /// The borrow checker ensures the transfer buffers can't borrow from
+    /// the data behind `guard`, but the caller must make sure that
+    /// releasing the lock is actually safe for their protocol:
+    ///
+    /// - The lock no longer serializes access to the device while the
+    ///   transfer is in flight.  The kernel performs each message
+    ///   atomically, but another thread may have its own message
+    ///   performed before or after this one.
+    /// - Anything protected by the lock may have been changed by another
+    ///   thread by the time `reacquire` returns, so it must be checked
+    ///   again rather than assumed to be as it was before the transfer.
+    pub fn transfer_unlocked<G, F>(&self,
+                                   guard: G,
+                                   transfer: &mut SpidevTransfer,
+                                   reacquire: F)
+                                   -> (G, io::Result<()>)
+        where F: FnOnce() -> G
+    {
+        if let Err(err) = self.prepare(std::slice::from_mut(transfer)) {
+            return (guard, Err(err));
+        }
+        drop(guard);
+        let result = spidevioctl::transfer(self.devfile.as_raw_fd(), transfer);
+        (reacquire(), result.map(|_| ()))
+    }
+
+    /// Perform a single transfer, also measuring how long it took
+    ///
+    /// Returns the number of bytes transferred and the time spent in the
+    /// ioctl; see `spidevioctl::transfer_timed`.
+    pub fn transfer_timed(&self, transfer: &mut SpidevTransfer) -> io::Result<(u32, Duration)> {
+        self.prepare(std::slice::from_mut(transfer))?;
+        spidevioctl::transfer_timed(self.devfile.as_raw_fd(), transfer)
+    }
+
+    /// Write `tx_buf` as a single transfer without a receive buffer
+    ///
+    /// Unlike `write`, this issues an `SPI_IOC_MESSAGE` ioctl, but as with
+    /// `write` nothing is read back: the kernel is given a null receive
+    /// buffer and the data clocked in is discarded.  Returns the number of
+    /// bytes transferred.
+    pub fn write_discard(&self, tx_buf: &[u8]) -> io::Result<u32> {
+        let mut transfer = SpidevTransfer::write(tx_buf);
+        self.prepare(std::slice::from_mut(&mut transfer))?;
+        spidevioctl::transfer(self.devfile.as_raw_fd(), &mut transfer)
+    }
+
+    /// Write `words` as 16-bit words, each laid out in `order`
+    ///
+    /// The transfer uses the device's word size.  With `bits_per_word` of
+    /// 9 to 16 the kernel expects `ByteOrder::Native`; with 8-bit words
+    /// `ByteOrder::Big` sends each word's high byte first.
+    pub fn write_words16(&self, words: &[u16], order: ByteOrder) -> io::Result<()> {
+        let tx_buf = order.encode_u16(words);
+        self.transfer(&mut SpidevTransfer::write(&tx_buf))
+    }
+
+    /// Read `count` 16-bit words, each laid out in `order`
+    ///
+    /// See `write_words16` for choosing the byte order.
+    pub fn read_words16(&self, count: usize, order: ByteOrder) -> io::Result<Vec<u16>> {
+        let mut rx_buf = vec![0; words_len(count, 2)?];
+        self.transfer(&mut SpidevTransfer::read(&mut rx_buf))?;
+        Ok(WordsU16::with_order(&rx_buf, order).collect())
+    }
+
+    /// Exchange 16-bit words full-duplex, laying out both directions in
+    /// `order`
+    ///
+    /// See `write_words16` for choosing the byte order.
+    pub fn transfer_words16(&self, words: &[u16], order: ByteOrder) -> io::Result<Vec<u16>> {
+        let tx_buf = order.encode_u16(words);
+        let mut rx_buf = vec![0; tx_buf.len()];
+        self.transfer(&mut SpidevTransfer::read_write(&tx_buf, &mut rx_buf))?;
+        Ok(WordsU16::with_order(&rx_buf, order).collect())
+    }
+
+    /// Write `words` as 32-bit words, each laid out in `order`
+    ///
+    /// With `bits_per_word` of 17 to 32 the kernel expects
+    /// `ByteOrder::Native`; see `write_words16`.
+    pub fn write_words32(&self, words: &[u32], order: ByteOrder) -> io::Result<()> {
+        let tx_buf = order.encode_u32(words);
+        self.transfer(&mut SpidevTransfer::write(&tx_buf))
+    }
+
+    /// Read `count` 32-bit words, each laid out in `order`
+    pub fn read_words32(&self, count: usize, order: ByteOrder) -> io::Result<Vec<u32>> {
+        let mut rx_buf = vec![0; words_len(count, 4)?];
+        self.transfer(&mut SpidevTransfer::read(&mut rx_buf))?;
+        Ok(WordsU32::with_order(&rx_buf, order).collect())
+    }
+
+    /// Exchange 32-bit words full-duplex, laying out both directions in
+    /// `order`
+    pub fn transfer_words32(&self, words: &[u32], order: ByteOrder) -> io::Result<Vec<u32>> {
+        let tx_buf = order.encode_u32(words);
+        let mut rx_buf = vec![0; tx_buf.len()];
+        self.transfer(&mut SpidevTransfer::read_write(&tx_buf, &mut rx_buf))?;
+        Ok(WordsU32::with_order(&rx_buf, order).collect())
+    }
+
+    /// Perform a full-duplex transfer of a `Bytes` buffer
+    ///
+    /// The transfer reads straight out of `tx_buf`, so data already held
+    /// in a `Bytes` (e.g. received from a socket) isn't copied again, and
+    /// the response is returned as a `BytesMut` of the same length.
+    /// Requires the `bytes` feature.
+    #[cfg(feature = "bytes")]
+    pub fn transfer_bytes(&self, tx_buf: &bytes::Bytes) -> io::Result<bytes::BytesMut> {
+        Spidev::transfer_bytes_with(tx_buf, |transfer| self.transfer(transfer))
+    }
+
+    /// `transfer_bytes`, performing the transfer with `run`
+    #[cfg(feature = "bytes")]
+    fn transfer_bytes_with<F>(tx_buf: &bytes::Bytes, run: F) -> io::Result<bytes::BytesMut>
+        where F: FnOnce(&mut SpidevTransfer) -> io::Result<()>
+    {
+        let mut rx_buf = bytes::BytesMut::with_capacity(tx_buf.len());
+        rx_buf.resize(tx_buf.len(), 0);
+        run(&mut SpidevTransfer::try_read_write(tx_buf, &mut rx_buf)?)?;
+        Ok(rx_buf)
+    }
+
+    /// Perform multiple transfers as one message with their buffers
+    /// locked into memory
+    ///
+    /// The buffers are locked with `mlock` before the transfer and
+    /// unlocked afterwards, so the transfer can't page fault; see the
+    /// `pinned` module for the `RLIMIT_MEMLOCK` limits involved.  Requires
+    /// the `mlock` feature.
+    #[cfg(feature = "mlock")]
+    pub fn transfer_pinned(&self, transfers: &mut [SpidevTransfer]) -> io::Result<()> {
+        self.prepare(transfers)?;
+        let fd = self.devfile.as_raw_fd();
+        pinned::with_locked(&pinned::Syscalls, transfers, |transfers| {
+            spidevioctl::transfer_multiple(fd, transfers)
+        })?;
+        Ok(())
+    }
+
+    /// Perform a full-duplex transfer of `tx_buf`, collecting the result
+    ///
+    /// The returned `TransferOutcome` holds the received bytes along with
+    /// the number of bytes the kernel reported as transferred.
+    pub fn transfer_outcome(&self, tx_buf: &[u8]) -> io::Result<TransferOutcome> {
+        let mut rx = vec![0; tx_buf.len()];
+        let bytes = {
+            let mut transfer = SpidevTransfer::read_write(tx_buf, &mut rx);
+            self.prepare(std::slice::from_mut(&mut transfer))?;
+            spidevioctl::transfer(self.devfile.as_raw_fd(), &mut transfer)?
+        };
+        Ok(TransferOutcome::new(rx, bytes))
     }
 
     /// Perform multiple transfers in a single system call to the kernel
@@ -252,7 +878,365 @@ impl Spidev {
     /// and be used for conveniently and efficient implementing some
     /// protocols without extra round trips back to userspace.
     pub fn transfer_multiple(&self, transfers: &mut [SpidevTransfer]) -> io::Result<()> {
-        spidevioctl::transfer_multiple(self.devfile.as_raw_fd(), transfers)
+        self.prepare(transfers)?;
+        spidevioctl::transfer_multiple(self.devfile.as_raw_fd(), transfers)?;
+        Ok(())
+    }
+
+    /// Run several transfers with chip select held asserted between them
+    ///
+    /// The closure is given a `CsHeldTransaction` through which it may
+    /// issue any number of transfers.  Each of those leaves chip select
+    /// asserted when it completes (by setting `cs_change` on it) and chip
+    /// select is released with an empty transfer once the closure
+    /// returns, whether or not it succeeded.  This models multi-step
+    /// protocols that expect a single chip select window.
+    ///
+    /// Note that the kernel treats `cs_change` on the final transfer of a
+    /// message as a hint; a controller may still deselect the device if
+    /// a message for another device on the same bus is queued between
+    /// the transfers issued here.
+    pub fn transfer_with_cs_held<F, T>(&self, f: F) -> io::Result<T>
+        where F: FnOnce(&mut CsHeldTransaction) -> io::Result<T>
+    {
+        let result = {
+            let mut transaction = CsHeldTransaction { spidev: self };
+            f(&mut transaction)
+        };
+        let mut release = SpidevTransfer::empty();
+        let released = self.transfer(&mut release);
+        let value = result?;
+        released?;
+        Ok(value)
+    }
+
+    /// Write a command and read the response within one chip select window
+    ///
+    /// The write and the read are issued as two transfers of a single
+    /// message, so chip select stays asserted between the command and
+    /// data phases.  This is the usual pattern for half-duplex devices.
+    ///
+    /// If `three_wire` is set and the device is not already in 3-wire
+    /// mode (`SPI_3WIRE`), for devices that drive the response on the
+    /// same line the command was sent on, it is switched into it for the
+    /// exchange and its previous mode is restored afterwards.
+    pub fn write_then_read(&self, tx_buf: &[u8], rx_len: usize, three_wire: bool) -> io::Result<Vec<u8>> {
+        if !three_wire {
+            return self.command_response(tx_buf, rx_len);
+        }
+        let fd = self.devfile.as_raw_fd();
+        // the raw mode, so bits SpiModeFlags doesn't define are kept
+        let mode = SpiModeFlags::from_raw(spidevioctl::get_mode32(fd)?);
+        if mode.contains(SpiModeFlags::SPI_3WIRE) {
+            return self.command_response(tx_buf, rx_len);
+        }
+        config::with_mode_with(&fd, mode | SpiModeFlags::SPI_3WIRE, || {
+            self.command_response(tx_buf, rx_len)
+        })
+    }
+
+    /// Perform a full-duplex transfer of `tx_buf` in chunks of up to
+    /// `chunk_size` bytes, calling `progress` after each
+    ///
+    /// Chip select stays asserted across the chunks; see
+    /// `chunked::transfer_chunked`.
+    pub fn transfer_chunked<P>(&self, tx_buf: &[u8], chunk_size: usize, progress: P) -> io::Result<Vec<u8>>
+        where P: FnMut(usize, usize)
+    {
+        chunked::transfer_chunked_spidev(self, tx_buf, chunk_size, progress)
+    }
+
+    /// Write `count` copies of `byte` without allocating `count` bytes
+    ///
+    /// See `chunked::write_repeated`.
+    pub fn write_repeated(&self, byte: u8, count: usize) -> io::Result<()> {
+        chunked::write_repeated_spidev(self, byte, count)
+    }
+
+    /// Perform a `(tx_buf, rx_len)` pair per transfer as one message,
+    /// returning the bytes received by each
+    ///
+    /// See `spidevioctl::transfer_all`.  The per-handle defaults are
+    /// applied as with `transfer_multiple`.
+    pub fn transfer_all(&self, steps: &[(&[u8], usize)]) -> io::Result<Vec<Vec<u8>>> {
+        spidevioctl::transfer_all_with(steps, |transfers| self.transfer_multiple(transfers))
+    }
+
+    /// Send a command and clock in a response of `resp_len` bytes
+    ///
+    /// Chip select stays asserted across both phases; see
+    /// `spidevioctl::command_response`.  The per-handle defaults are
+    /// applied to both transfers as with `transfer_multiple`.
+    pub fn command_response(&self, cmd: &[u8], resp_len: usize) -> io::Result<Vec<u8>> {
+        spidevioctl::command_response_with(cmd, resp_len, |transfers| self.transfer_multiple(transfers))
+    }
+
+    /// Run `f` with the device switched to `mode`, then restore the
+    /// previous mode
+    ///
+    /// The previous mode is restored even if `f` returns an error or
+    /// panics; see `config::with_mode`.  Note that `mode` replaces the
+    /// whole mode, including option bits such as `SPI_CS_HIGH`.
+    pub fn with_mode<F, T>(&self, mode: SpiModeFlags, f: F) -> io::Result<T>
+        where F: FnOnce(&Spidev) -> io::Result<T>
+    {
+        config::with_mode(self.devfile.as_raw_fd(), mode, || f(self))
+    }
+
+    /// Check whether a device appears to be responding
+    ///
+    /// See `diagnostics::probe_present` for how the response to
+    /// `tx_buf` is interpreted; SPI has no acknowledgement so this is only
+    /// a heuristic.
+    pub fn probe_present(&self, tx_buf: &[u8]) -> io::Result<Presence> {
+        diagnostics::probe_present_with(tx_buf, |transfer| self.transfer(transfer))
+    }
+
+    /// Put the device in (or take it out of) non-blocking mode
+    ///
+    /// With `O_NONBLOCK` set, operations that would block fail with an
+    /// error of kind `WouldBlock` (`EAGAIN`) instead.  Most spidev
+    /// transfers complete synchronously, so this is of limited use on
+    /// its own, but it allows the handle to be used alongside `poll` in
+    /// an event loop.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        let fd = self.devfile.as_raw_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let flags = if nonblocking {
+            flags | libc::O_NONBLOCK
+        } else {
+            flags & !libc::O_NONBLOCK
+        };
+        if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Wait until the device can accept a transfer
+    ///
+    /// Fails with `TimedOut` if it isn't ready within `timeout`.  Few
+    /// drivers signal readiness; see `ready::wait_ready`.
+    pub fn wait_ready(&self, timeout: Duration) -> io::Result<()> {
+        ready::wait_ready(self.devfile.as_raw_fd(), timeout)
+    }
+
+    /// Check that data generated by `pattern` survives a loopback
+    ///
+    /// Returns the index of the first mismatching byte, if any.  See
+    /// `diagnostics::integrity_test`.
+    pub fn integrity_test(&self, pattern: &dyn Fn(usize) -> u8, len: usize) -> io::Result<Option<usize>> {
+        diagnostics::integrity_test_with(pattern, len, |transfer| self.transfer(transfer))
+    }
+
+    /// Read the modalias used to bind a driver to this device from sysfs
+    ///
+    /// See `sysfs::modalias`.
+    pub fn modalias(&self) -> io::Result<String> {
+        sysfs::modalias(self.devfile.as_raw_fd())
+    }
+
+    /// Perform `transfer` and check it took at least its theoretical
+    /// minimum time
+    ///
+    /// See `diagnostics::check_timing`.
+    pub fn check_timing(&self, transfer: &mut SpidevTransfer) -> io::Result<TimingCheck> {
+        self.prepare(std::slice::from_mut(transfer))?;
+        diagnostics::check_timing(self.devfile.as_raw_fd(), transfer)
+    }
+
+    /// Read a few bytes and check whether MISO looks stuck high or low
+    ///
+    /// See `diagnostics::diagnose_wiring`.
+    pub fn diagnose_wiring(&self) -> io::Result<Wiring> {
+        diagnostics::diagnose_wiring_with(&self.devfile.as_raw_fd(), |transfer| self.transfer(transfer))
+    }
+
+    /// Look up the SPI controller and driver backing this device in sysfs
+    pub fn controller_info(&self) -> io::Result<ControllerInfo> {
+        sysfs::controller_info(self.devfile.as_raw_fd())
+    }
+
+    /// Read the kernel's transfer counters for this device from sysfs
+    ///
+    /// `None` is returned if the kernel does not keep SPI statistics.
+    pub fn statistics(&self) -> io::Result<Option<Statistics>> {
+        sysfs::statistics(self.devfile.as_raw_fd())
+    }
+}
+
+/// The complete result of `Spidev::transfer_outcome`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferOutcome {
+    /// The bytes received during the transfer
+    pub rx: Vec<u8>,
+    /// The number of bytes the kernel reported as transferred
+    pub bytes: u32,
+    /// Whether fewer bytes were transferred than were requested
+    pub short: bool,
+}
+
+impl TransferOutcome {
+    fn new(rx: Vec<u8>, bytes: u32) -> TransferOutcome {
+        let short = (bytes as usize) < rx.len();
+        TransferOutcome { rx, bytes, short }
+    }
+}
+
+/// Formats a byte slice as hex with `{:x}` or `{:X}`
+///
+/// Bytes are written as two digits each, separated by spaces; the
+/// alternate flag (`{:#x}`) prefixes each byte with `0x`.  This is
+/// convenient for logging the buffers of a transfer.
+///
+/// ```
+/// use spidev::HexSlice;
+/// assert_eq!(format!("{:x}", HexSlice(&[0x0a, 0xff])), "0a ff");
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct HexSlice<'a>(pub &'a [u8]);
+
+impl<'a> HexSlice<'a> {
+    fn fmt_with(&self, f: &mut fmt::Formatter, upper: bool) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i != 0 {
+                f.write_str(" ")?;
+            }
+            match (f.alternate(), upper) {
+                (false, false) => write!(f, "{:02x}", byte)?,
+                (false, true) => write!(f, "{:02X}", byte)?,
+                (true, false) => write!(f, "{:#04x}", byte)?,
+                (true, true) => write!(f, "0x{:02X}", byte)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> fmt::LowerHex for HexSlice<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_with(f, false)
+    }
+}
+
+impl<'a> fmt::UpperHex for HexSlice<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_with(f, true)
+    }
+}
+
+/// Transfers issued while chip select is held asserted
+///
+/// See `Spidev::transfer_with_cs_held`.
+pub struct CsHeldTransaction<'a> {
+    spidev: &'a Spidev,
+}
+
+impl<'a> CsHeldTransaction<'a> {
+    /// Perform a single transfer, leaving chip select asserted
+    pub fn transfer(&mut self, transfer: &mut SpidevTransfer) -> io::Result<()> {
+        transfer.cs_change = 1;
+        self.spidev.transfer(transfer)
+    }
+
+    /// Write the provided bytes, discarding anything received
+    pub fn write(&mut self, tx_buf: &[u8]) -> io::Result<()> {
+        self.transfer(&mut SpidevTransfer::write(tx_buf))
+    }
+
+    /// Fill the provided buffer, shifting out zeroes
+    pub fn read(&mut self, rx_buf: &mut [u8]) -> io::Result<()> {
+        self.transfer(&mut SpidevTransfer::read(rx_buf))
+    }
+}
+
+/// Configuration changes collected to be applied together
+///
+/// See `Spidev::config`.  The setters are those of `SpidevOptions`.
+pub struct ConfigTransaction<'a> {
+    spidev: &'a mut Spidev,
+    options: SpidevOptions,
+}
+
+impl<'a> ConfigTransaction<'a> {
+    /// The number of bits in each SPI transfer word
+    pub fn bits_per_word(&mut self, bits_per_word: u8) -> &mut Self {
+        self.options.bits_per_word(bits_per_word);
+        self
+    }
+
+    /// The number of bits in each SPI transfer word, as a `WordSize`
+    pub fn word_size(&mut self, size: WordSize) -> &mut Self {
+        self.options.word_size(size);
+        self
+    }
+
+    /// The maximum SPI transfer speed, in Hz
+    pub fn max_speed_hz(&mut self, max_speed_hz: u32) -> &mut Self {
+        self.options.max_speed_hz(max_speed_hz);
+        self
+    }
+
+    /// The bit justification used to transfer SPI words
+    pub fn lsb_first(&mut self, lsb_first: bool) -> &mut Self {
+        self.options.lsb_first(lsb_first);
+        self
+    }
+
+    /// The SPI mode, replacing the whole mode
+    pub fn mode(&mut self, mode: SpiModeFlags) -> &mut Self {
+        self.options.mode(mode);
+        self
+    }
+
+    /// The delay used for transfers that don't specify their own
+    pub fn delay_usecs(&mut self, delay_usecs: u16) -> &mut Self {
+        self.options.delay_usecs(delay_usecs);
+        self
+    }
+
+    /// Apply the collected changes, returning which settings were written
+    ///
+    /// As with `Spidev::apply_diff`, each setting is read back first and
+    /// only written if it differs.  If a write fails the error wraps a
+    /// `config::StepFailed` naming the setting and those already
+    /// written, which remain applied.
+    pub fn commit(&mut self) -> io::Result<ChangeSet> {
+        let fd = self.spidev.devfile.as_raw_fd();
+        let result = if self.spidev.verify_writes {
+            config::commit_with(&config::Verified(&fd), &self.options)
+        } else {
+            config::commit_with(&fd, &self.options)
+        };
+        match result {
+            Ok(_) => self.spidev.record_applied(&self.options),
+            Err(ref err) => {
+                // the settings written before the failure remain applied
+                let failed = err.get_ref().and_then(|err| err.downcast_ref::<config::StepFailed>());
+                if let Some(failed) = failed {
+                    self.spidev.record_applied(&failed.applied_options(&self.options));
+                }
+            }
+        }
+        result
+    }
+}
+
+/// The length in bytes of `count` words of `word_bytes` each
+///
+/// A count too large to allocate is rejected rather than wrapping to a
+/// short buffer.
+fn words_len(count: usize, word_bytes: usize) -> io::Result<usize> {
+    count.checked_mul(word_bytes)
+         .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "word count is too large"))
+}
+
+impl AsRawFd for Spidev {
+    fn as_raw_fd(&self) -> RawFd {
+        self.devfile.as_raw_fd()
     }
 }
 
@@ -275,7 +1259,28 @@ impl Write for Spidev {
 #[cfg(test)]
 mod test {
 
-    use super::{SpidevOptions, SpiModeFlags};
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::os::unix::prelude::*;
+    use std::path::Path;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use sysfs::test::scratch_dir;
+    use libc;
+    use super::{words_len, ByteOrder, HexSlice, Spidev, SpidevOptions, SpidevTransfer, SpiModeFlags,
+                TransferOutcome, WordSize};
+
+    /// Open the device used by the hardware tests
+    ///
+    /// These tests are ignored by default; run them with `cargo test --
+    /// --ignored` on a target with the device named by
+    /// `SPIDEV_TEST_DEVICE` (default `/dev/spidev0.0`).  Tests that
+    /// check received data expect MOSI to be looped back to MISO.
+    pub fn test_device() -> Spidev {
+        let path = env::var("SPIDEV_TEST_DEVICE").unwrap_or_else(|_| "/dev/spidev0.0".to_string());
+        Spidev::open(path).unwrap()
+    }
 
     #[test]
     fn test_spidev_options_all() {
@@ -291,6 +1296,41 @@ mod test {
         assert_eq!(options.spi_mode, Some(SpiModeFlags::SPI_MODE_0));
     }
 
+    #[test]
+    fn test_words_len() {
+        assert_eq!(words_len(3, 2).unwrap(), 6);
+        assert_eq!(words_len(usize::MAX / 4, 4).unwrap(), usize::MAX / 4 * 4);
+        let err = words_len(usize::MAX / 4 + 1, 4).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(words_len(usize::MAX / 2 + 1, 2).is_err());
+    }
+
+    #[test]
+    fn test_spidev_options_none() {
+        let options = SpidevOptions::new().build();
+        assert_eq!(options.bits_per_word, None);
+        assert_eq!(options.max_speed_hz, None);
+        assert_eq!(options.lsb_first, None);
+        assert_eq!(options.spi_mode, None);
+        assert_eq!(options, SpidevOptions::default());
+    }
+
+    #[test]
+    fn test_spidev_options_chained() {
+        let options = SpidevOptions::new()
+                          .bits_per_word(8)
+                          .max_speed_hz(1_000_000)
+                          .mode(SpiModeFlags::SPI_MODE_3)
+                          .lsb_first(false)
+                          .build();
+        let mut expected = SpidevOptions::new();
+        expected.bits_per_word = Some(8);
+        expected.max_speed_hz = Some(1_000_000);
+        expected.spi_mode = Some(SpiModeFlags::SPI_MODE_3);
+        expected.lsb_first = Some(false);
+        assert_eq!(options, expected);
+    }
+
     #[test]
     fn test_spidev_options_some() {
         let mut options = SpidevOptions::new();
@@ -301,4 +1341,381 @@ mod test {
         assert_eq!(options.lsb_first, Some(true));
         assert_eq!(options.spi_mode, None);
     }
+
+    #[test]
+    fn test_mode_bits_round_trip() {
+        for &bits in &[0x00, 0x03, 0x0f, 0xff, 0x100, 0xf00, 0xfff] {
+            assert_eq!(SpiModeFlags::from_mode_bits(bits).unwrap().bits(), bits);
+        }
+        for byte in 0..=255u8 {
+            assert_eq!(SpiModeFlags::from_mode_byte(byte).bits(), u32::from(byte));
+        }
+    }
+
+    #[test]
+    fn test_mode_bits_unknown() {
+        // SPI_CS_WORD is defined by newer kernels but not by this crate;
+        // it must be rejected rather than truncated away
+        let err = SpiModeFlags::from_mode_bits(0x1000 | 0x01).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(SpiModeFlags::from_mode_bits(0x8000_0000).is_err());
+    }
+
+    #[test]
+    fn test_hex_slice() {
+        let bytes = [0x00, 0x0f, 0xa5, 0xff];
+        assert_eq!(format!("{:x}", HexSlice(&bytes)), "00 0f a5 ff");
+        assert_eq!(format!("{:X}", HexSlice(&bytes)), "00 0F A5 FF");
+        assert_eq!(format!("{:#x}", HexSlice(&bytes)), "0x00 0x0f 0xa5 0xff");
+        assert_eq!(format!("{:#X}", HexSlice(&bytes)), "0x00 0x0F 0xA5 0xFF");
+        assert_eq!(format!("{:x}", HexSlice(&[])), "");
+    }
+
+    #[test]
+    fn test_active_speed_hz_unconfigured() {
+        let mut spidev = Spidev::open("/dev/null").unwrap();
+        assert_eq!(spidev.active_speed_hz(), None);
+
+        // the ioctl fails on a file that is not a spidev device, so
+        // the cached speed must not be updated
+        let options = SpidevOptions::new().max_speed_hz(20_000).build();
+        assert!(spidev.configure(&options).is_err());
+        assert_eq!(spidev.active_speed_hz(), None);
+        assert!(spidev.set_max_speed_hz(20_000).is_err());
+        assert_eq!(spidev.active_speed_hz(), None);
+    }
+
+    #[test]
+    fn test_transfer_with_cs_held_propagates_errors() {
+        let spidev = Spidev::open("/dev/null").unwrap();
+        let mut called = false;
+        let result = spidev.transfer_with_cs_held(|txn| {
+            called = true;
+            txn.write(&[0x01])
+        });
+        assert!(called);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_open_with_options_closes_on_error() {
+        // a regular file stands in for a device that rejects the
+        // configuration ioctls
+        let path = scratch_dir("open-with-options").join("spidev0.0");
+        fs::write(&path, b"").unwrap();
+        let options = SpidevOptions::new().bits_per_word(8).build();
+        assert!(Spidev::open_with_options(&path, &options).is_err());
+
+        let leaked = fs::read_dir("/proc/self/fd").unwrap().any(|entry| {
+            fs::read_link(entry.unwrap().path()).ok().map_or(false, |target| target == path)
+        });
+        assert!(!leaked);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn test_open_with_options_device() {
+        let path = env::var("SPIDEV_TEST_DEVICE").unwrap_or_else(|_| "/dev/spidev0.0".to_string());
+        let options = SpidevOptions::new().max_speed_hz(20_000).build();
+        let spidev = Spidev::open_with_options(path, &options).unwrap();
+        assert_eq!(spidev.max_speed_hz().unwrap(), 20_000);
+    }
+
+    #[test]
+    fn test_set_nonblocking() {
+        let spidev = Spidev::open("/dev/null").unwrap();
+        let flags = || unsafe { libc::fcntl(spidev.as_raw_fd(), libc::F_GETFL) };
+        assert_eq!(flags() & libc::O_NONBLOCK, 0);
+        spidev.set_nonblocking(true).unwrap();
+        assert_eq!(flags() & libc::O_NONBLOCK, libc::O_NONBLOCK);
+        spidev.set_nonblocking(false).unwrap();
+        assert_eq!(flags() & libc::O_NONBLOCK, 0);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_transfer_with_cs_held_loopback() {
+        let spidev = test_device();
+        let tx_cmd = [0x9f];
+        let tx_data = [0x01, 0x02, 0x03];
+        let mut rx_cmd = [0; 1];
+        let mut rx_data = [0; 3];
+        spidev.transfer_with_cs_held(|txn| {
+            txn.transfer(&mut SpidevTransfer::read_write(&tx_cmd, &mut rx_cmd))?;
+            txn.transfer(&mut SpidevTransfer::read_write(&tx_data, &mut rx_data))
+        }).unwrap();
+        assert_eq!(rx_cmd, tx_cmd);
+        assert_eq!(rx_data, tx_data);
+    }
+
+    #[test]
+    fn test_transfer_outcome_short() {
+        assert!(!TransferOutcome::new(vec![0; 4], 4).short);
+        assert!(TransferOutcome::new(vec![0; 4], 3).short);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_transfer_outcome_loopback() {
+        let spidev = test_device();
+        let outcome = spidev.transfer_outcome(&[0x01, 0x02, 0x03]).unwrap();
+        assert_eq!(outcome.rx, [0x01, 0x02, 0x03]);
+        assert_eq!(outcome.bytes, 3);
+        assert!(!outcome.short);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_write_then_read_loopback() {
+        // nothing is shifted out during the read phase, so a looped back
+        // device reads zeroes
+        let spidev = test_device();
+        let rx_buf = spidev.write_then_read(&[0x03, 0x00], 4, false).unwrap();
+        assert_eq!(rx_buf, [0; 4]);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_words_byte_order_loopback() {
+        let spidev = test_device();
+        let words16 = [0x0102u16, 0xabcd];
+        let words32 = [0x0102_0304u32, 0x00ab_cdef];
+        // requires MOSI to be looped back to MISO
+        for &order in &[ByteOrder::Little, ByteOrder::Big, ByteOrder::Native] {
+            assert_eq!(spidev.transfer_words16(&words16, order).unwrap(), words16);
+            assert_eq!(spidev.transfer_words32(&words32, order).unwrap(), words32);
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn test_transfer_all_loopback() {
+        let spidev = test_device();
+        let cmd = [0x0b, 0x00];
+        let data = [0x01, 0x02, 0x03, 0x04, 0x05];
+        let rx_bufs = spidev.transfer_all(&[(&cmd, 0), (&[], 8), (&data, 5)]).unwrap();
+        assert!(rx_bufs[0].is_empty());
+        // requires MOSI to be looped back to MISO; nothing is shifted out
+        // during the read
+        assert_eq!(rx_bufs[1], [0; 8]);
+        assert_eq!(rx_bufs[2], data);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_command_response_loopback() {
+        let spidev = test_device();
+        let resp = spidev.command_response(&[0x9f], 16).unwrap();
+        // the response phase is separate from the command, so a looped
+        // back device reads back the zeroes shifted out rather than the
+        // command byte
+        assert_eq!(resp, [0; 16]);
+        assert!(spidev.command_response(&[0x9f, 0x00, 0x00], 0).unwrap().is_empty());
+    }
+
+    #[test]
+    #[ignore]
+    fn test_active_speed_hz_matches_kernel() {
+        let mut spidev = test_device();
+        let options = SpidevOptions::new().max_speed_hz(20_000).build();
+        spidev.configure(&options).unwrap();
+        assert_eq!(spidev.active_speed_hz(), Some(20_000));
+        assert_eq!(spidev.active_speed_hz(), Some(spidev.max_speed_hz().unwrap()));
+        spidev.set_max_speed_hz(40_000).unwrap();
+        assert_eq!(spidev.active_speed_hz(), Some(40_000));
+        assert_eq!(spidev.active_speed_hz(), Some(spidev.max_speed_hz().unwrap()));
+    }
+
+    #[test]
+    fn test_default_delay_usecs() {
+        let mut spidev = Spidev::open("/dev/null").unwrap();
+        // delay_usecs is not a kernel setting, so this succeeds on /dev/null
+        spidev.configure(&SpidevOptions::new().delay_usecs(25).build()).unwrap();
+        assert_eq!(spidev.default_delay_usecs(), Some(25));
+
+        let tx_buf = [0; 2];
+        let mut transfers = [SpidevTransfer::write(&tx_buf),
+                             SpidevTransfer::write(&tx_buf).delay_usecs(5u16)];
+        // the defaults are filled in before the ioctl fails with ENOTTY
+        assert!(spidev.transfer_multiple(&mut transfers).is_err());
+        assert_eq!(transfers[0].delay_usecs, 25);
+        assert_eq!(transfers[1].delay_usecs, 5);
+    }
+
+    #[test]
+    fn test_open_slave_without_sysfs() {
+        // /dev/null has no spidev sysfs entry, so it can't be checked
+        let err = Spidev::open_slave("/dev/null").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(!Spidev::open("/dev/null").unwrap().is_slave());
+    }
+
+    #[test]
+    #[ignore]
+    fn test_open_slave_device() {
+        // needs a remote master clocking data in on the slave controller
+        let path = env::var("SPIDEV_TEST_SLAVE_DEVICE").unwrap_or_else(|_| "/dev/spidev1.0".to_string());
+        let spidev = Spidev::open_slave(path).unwrap();
+        assert!(spidev.is_slave());
+        let mut rx_buf = [0; 4];
+        spidev.transfer(&mut SpidevTransfer::read(&mut rx_buf)).unwrap();
+    }
+
+    #[test]
+    fn test_reopen() {
+        let mut spidev = Spidev::open("/dev/null").unwrap();
+        spidev.configure(&SpidevOptions::new().delay_usecs(25).build()).unwrap();
+        let tx_buf = [0; 2];
+        assert!(spidev.transfer(&mut SpidevTransfer::write(&tx_buf)).is_err());
+
+        spidev.reopen().unwrap();
+        assert_eq!(spidev.default_delay_usecs(), Some(25));
+        assert_eq!(fs::read_link(format!("/proc/self/fd/{}", spidev.as_raw_fd())).unwrap(),
+                   Path::new("/dev/null"));
+    }
+
+    #[test]
+    #[ignore]
+    fn test_configure_and_transfer_device() {
+        let mut spidev = test_device();
+        spidev.configure(&SpidevOptions::new().bits_per_word(8).build()).unwrap();
+        let options = SpidevOptions::new().bits_per_word(16).delay_usecs(10).build();
+        // the transfer is checked against the word size being applied
+        let tx_buf = [0x01, 0x02, 0x03];
+        let err = spidev.configure_and_transfer(&options, &mut SpidevTransfer::write(&tx_buf), true)
+                        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(spidev.word_size().unwrap(), WordSize::Bits8);
+        assert_eq!(spidev.default_delay_usecs(), None);
+
+        let tx_buf = [0x01, 0x02, 0x03, 0x04];
+        let mut transfer = SpidevTransfer::write(&tx_buf);
+        spidev.configure_and_transfer(&options, &mut transfer, false).unwrap();
+        assert_eq!(transfer.delay_usecs, 10);
+        assert_eq!(spidev.word_size().unwrap(), WordSize::Bits16);
+        assert_eq!(spidev.default_delay_usecs(), Some(10));
+    }
+
+    #[test]
+    #[ignore]
+    fn test_reopen_device() {
+        let mut spidev = test_device();
+        let options = SpidevOptions::new()
+                          .max_speed_hz(50_000)
+                          .mode(SpiModeFlags::SPI_MODE_1)
+                          .build();
+        spidev.configure(&options).unwrap();
+        spidev.set_mode_preserving_options(SpiModeFlags::SPI_MODE_3).unwrap();
+        spidev.reopen().unwrap();
+        assert_eq!(spidev.max_speed_hz().unwrap(), 50_000);
+        assert_eq!(spidev.active_speed_hz(), Some(50_000));
+        // the mode changed after configure is reapplied too
+        assert_eq!(spidev.current_state().unwrap().spi_mode.unwrap() & SpiModeFlags::SPI_MODE_3,
+                   SpiModeFlags::SPI_MODE_3);
+        spidev.write_discard(&[0x55; 4]).unwrap();
+    }
+
+    #[test]
+    fn test_word_size_options() {
+        let options = SpidevOptions::new().word_size(WordSize::Bits12).build();
+        assert_eq!(options.bits_per_word, Some(12));
+        let tx_buf = [0; 4];
+        assert_eq!(SpidevTransfer::write(&tx_buf).word_size(WordSize::Bits12).bits_per_word, 12);
+    }
+
+    #[test]
+    fn test_transfer_unlocked_releases_lock() {
+        let spidev = Spidev::open("/dev/null").unwrap();
+        let state = Mutex::new(0);
+        let guard = state.lock().unwrap();
+        let tx_buf = [0; 2];
+        let mut transfer = SpidevTransfer::write(&tx_buf);
+        let (mut guard, result) = spidev.transfer_unlocked(guard, &mut transfer, || {
+            // the lock was released before the transfer
+            assert!(state.try_lock().is_ok());
+            state.lock().unwrap()
+        });
+        assert!(result.is_err());
+        *guard += 1;
+        assert!(state.try_lock().is_err());
+        drop(guard);
+        assert_eq!(*state.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_transfer_unlocked_invalid_keeps_lock() {
+        let spidev = Spidev::open("/dev/null").unwrap();
+        let state = Mutex::new(());
+        let tx_buf = [0; 2];
+        let mut transfer = SpidevTransfer::write(&tx_buf);
+        transfer.bits_per_word = 33;
+        let (_guard, result) = spidev.transfer_unlocked(state.lock().unwrap(),
+                                                        &mut transfer,
+                                                        || panic!("lock was released"));
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    #[cfg(feature = "bytes")]
+    fn test_transfer_bytes_error() {
+        let spidev = Spidev::open("/dev/null").unwrap();
+        let tx_buf = ::bytes::Bytes::from_static(&[0x01, 0x02]);
+        assert!(spidev.transfer_bytes(&tx_buf).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "bytes")]
+    fn test_transfer_bytes_with() {
+        let tx_buf = ::bytes::Bytes::from(vec![0x55, 0xaa, 0x01, 0x80]);
+        let rx_buf = Spidev::transfer_bytes_with(&tx_buf, |transfer| {
+                         // the transfer reads straight out of the Bytes
+                         assert_eq!(transfer.tx_slice().as_ptr(), tx_buf.as_ptr());
+                         let sent = transfer.tx_slice().to_vec();
+                         transfer.rx_slice_mut().copy_from_slice(&sent);
+                         Ok(())
+                     })
+                         .unwrap();
+        assert_eq!(rx_buf.freeze(), tx_buf);
+    }
+
+    #[test]
+    #[ignore]
+    #[cfg(feature = "bytes")]
+    fn test_transfer_bytes_loopback() {
+        let spidev = test_device();
+        let tx_buf = ::bytes::Bytes::from(vec![0x55, 0xaa, 0x01, 0x80]);
+        let rx_buf = spidev.transfer_bytes(&tx_buf).unwrap();
+        assert_eq!(rx_buf.freeze(), tx_buf);
+    }
+
+    #[test]
+    fn test_session_bits_per_word() {
+        let mut spidev = Spidev::open("/dev/null").unwrap();
+        spidev.set_session_bits_per_word(16);
+        assert_eq!(spidev.session_bits_per_word(), Some(16));
+
+        let tx_buf = [0; 4];
+        let mut transfers = [SpidevTransfer::write(&tx_buf),
+                             SpidevTransfer::write(&tx_buf).word_size(WordSize::Bits8)];
+        // the defaults are filled in before the ioctl fails with ENOTTY
+        assert!(spidev.transfer_multiple(&mut transfers).is_err());
+        assert_eq!(transfers[0].bits_per_word, 16);
+        assert_eq!(transfers[1].bits_per_word, 8);
+
+        // the session default is also used to check alignment
+        let odd = [0; 3];
+        let err = spidev.transfer(&mut SpidevTransfer::write(&odd)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        spidev.set_session_bits_per_word(0);
+        assert_eq!(spidev.session_bits_per_word(), None);
+    }
+
+    #[test]
+    fn test_wait_ready() {
+        // like spidev, /dev/null has no poll handler and is always ready
+        let spidev = Spidev::open("/dev/null").unwrap();
+        spidev.wait_ready(Duration::from_millis(10)).unwrap();
+    }
 }
@@ -0,0 +1,329 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Building messages made up of several transfers
+//!
+//! A `Message` describes a sequence of transfers that are performed
+//! together in a single `SPI_IOC_MESSAGE` ioctl, with receive buffers
+//! allocated as needed.  The `transfer!` macro provides a terse way of
+//! writing one.
+
+use std::io;
+use super::{Spidev, SpidevTransfer};
+use spidevioctl::InvalidTransfer;
+
+/// One transfer of a `Message`
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Step<'a> {
+    tx_buf: Option<&'a [u8]>,
+    len: usize,
+    reads: bool,
+    speed_hz: u32,
+    delay_usecs: u16,
+    word_delay_usecs: u8,
+    bits_per_word: u8,
+    cs_change: bool,
+}
+
+/// A sequence of transfers performed as a single message
+///
+/// Transfers are added with `write`, `read` and `read_write`; the
+/// override methods (`speed_hz`, `delay_usecs`, ...) apply to the most
+/// recently added transfer and panic if there is none.
+///
+/// ```no_run
+/// # use spidev::{Message, Spidev};
+/// let spidev = Spidev::open("/dev/spidev0.0").unwrap();
+/// let responses = Message::new()
+///                     .write(&[0x03, 0x00, 0x10])
+///                     .read(4)
+///                     .speed_hz(500_000)
+///                     .run(&spidev)
+///                     .unwrap();
+/// println!("{:?}", responses[0]);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Message<'a> {
+    steps: Vec<Step<'a>>,
+    keep_cs_asserted: Option<bool>,
+}
+
+impl<'a> Message<'a> {
+    /// Create a new, empty message
+    pub fn new() -> Message<'a> {
+        Message {
+            steps: Vec::new(),
+            keep_cs_asserted: None,
+        }
+    }
+
+    /// Add a transfer writing `tx_buf`, discarding anything received
+    pub fn write(&mut self, tx_buf: &'a [u8]) -> &mut Self {
+        self.steps.push(Step {
+            tx_buf: Some(tx_buf),
+            len: tx_buf.len(),
+            ..Default::default()
+        });
+        self
+    }
+
+    /// Add a transfer reading `len` bytes while shifting out zeroes
+    pub fn read(&mut self, len: usize) -> &mut Self {
+        self.steps.push(Step {
+            len,
+            reads: true,
+            ..Default::default()
+        });
+        self
+    }
+
+    /// Add a full-duplex transfer writing `tx_buf` and reading as many
+    /// bytes as it holds
+    pub fn read_write(&mut self, tx_buf: &'a [u8]) -> &mut Self {
+        self.steps.push(Step {
+            tx_buf: Some(tx_buf),
+            len: tx_buf.len(),
+            reads: true,
+            ..Default::default()
+        });
+        self
+    }
+
+    fn last(&mut self) -> &mut Step<'a> {
+        self.steps.last_mut().expect("message has no transfer to apply the option to")
+    }
+
+    /// Override the speed of the last transfer
+    pub fn speed_hz(&mut self, speed_hz: u32) -> &mut Self {
+        self.last().speed_hz = speed_hz;
+        self
+    }
+
+    /// Delay after the last transfer, before optionally deselecting the
+    /// device
+    pub fn delay_usecs(&mut self, delay_usecs: u16) -> &mut Self {
+        self.last().delay_usecs = delay_usecs;
+        self
+    }
+
+    /// Delay between the words of the last transfer
+    pub fn word_delay_usecs(&mut self, word_delay_usecs: u8) -> &mut Self {
+        self.last().word_delay_usecs = word_delay_usecs;
+        self
+    }
+
+    /// Override the word size of the last transfer
+    pub fn bits_per_word(&mut self, bits_per_word: u8) -> &mut Self {
+        self.last().bits_per_word = bits_per_word;
+        self
+    }
+
+    /// Deselect the device after the last transfer
+    pub fn cs_change(&mut self, cs_change: bool) -> &mut Self {
+        self.last().cs_change = cs_change;
+        self
+    }
+
+    /// Leave the device selected after the message completes
+    ///
+    /// This sets the `cs_change` flag of the final transfer, overriding
+    /// any `cs_change` given for it.  Counterintuitively, on the last
+    /// transfer of a message `cs_change` means the kernel does *not*
+    /// deselect the device afterwards, so chip select stays asserted
+    /// until the next message, which some protocols rely on.  Note that
+    /// a message to another device on the same bus in the meantime will
+    /// deselect it.  Passing `false` makes sure chip select is released
+    /// as usual.
+    pub fn keep_cs_asserted(&mut self, keep: bool) -> &mut Self {
+        self.keep_cs_asserted = Some(keep);
+        self
+    }
+
+    /// The receive buffers for the transfers that read
+    fn rx_bufs(&self) -> Vec<Vec<u8>> {
+        self.steps
+            .iter()
+            .filter(|step| step.reads)
+            .map(|step| vec![0; step.len])
+            .collect()
+    }
+
+    /// Build the transfers, reading into `rx_bufs`
+    fn transfers<'b>(&self, rx_bufs: &'b mut [Vec<u8>]) -> Result<Vec<SpidevTransfer<'a, 'b>>, InvalidTransfer> {
+        let mut rx_iter = rx_bufs.iter_mut();
+        let mut transfers = Vec::with_capacity(self.steps.len());
+        for (index, step) in self.steps.iter().enumerate() {
+            let transfer = match (step.tx_buf, step.reads) {
+                (Some(tx_buf), true) => {
+                    SpidevTransfer::try_read_write(tx_buf, rx_iter.next().unwrap())
+                }
+                (Some(tx_buf), false) => SpidevTransfer::try_write(tx_buf),
+                (None, _) => SpidevTransfer::try_read(rx_iter.next().unwrap()),
+            };
+            let mut transfer = transfer.map_err(|_| {
+                InvalidTransfer {
+                    index,
+                    reason: "buffer is longer than u32::MAX bytes",
+                }
+            })?;
+            transfer.speed_hz = step.speed_hz;
+            transfer.delay_usecs = step.delay_usecs;
+            transfer.word_delay_usecs = step.word_delay_usecs;
+            transfer.bits_per_word = step.bits_per_word;
+            transfer.cs_change = step.cs_change as u8;
+            transfers.push(transfer);
+        }
+        if let (Some(keep), Some(last)) = (self.keep_cs_asserted, transfers.last_mut()) {
+            last.cs_change = keep as u8;
+        }
+        Ok(transfers)
+    }
+
+    /// Perform the message on `spidev`
+    ///
+    /// Returns the bytes received by each `read` and `read_write`
+    /// transfer, in the order they were added.  If any transfer is
+    /// invalid nothing is performed and an `InvalidTransfer` error
+    /// identifies it.
+    pub fn run(&self, spidev: &Spidev) -> io::Result<Vec<Vec<u8>>> {
+        let mut rx_bufs = self.rx_bufs();
+        spidev.transfer_multiple(&mut self.transfers(&mut rx_bufs)?)?;
+        Ok(rx_bufs)
+    }
+}
+
+/// Perform a sequence of transfers as a single message
+///
+/// Each transfer is one of `write <buffer>`, `read <length>` or
+/// `read_write <buffer>`, optionally followed by `=> { ... }` with
+/// overrides for that transfer using the names of the `Message` override
+/// methods.  The macro evaluates to the result of `Message::run`: the
+/// bytes received by each `read` and `read_write`, in order.
+///
+/// ```no_run
+/// #[macro_use]
+/// extern crate spidev;
+/// use spidev::Spidev;
+///
+/// # fn main() {
+/// let spidev = Spidev::open("/dev/spidev0.0").unwrap();
+/// let cmd = [0x0b, 0x00, 0x00, 0x00];
+/// let data = [0xde, 0xad];
+/// let responses = transfer!(spidev,
+///                           write &cmd,
+///                           read 4 => { speed_hz: 500_000, delay_usecs: 10 },
+///                           write &data).unwrap();
+/// println!("{:?}", responses[0]);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! transfer {
+    ($spidev:expr, $($kind:ident $arg:expr $(=> { $($opt:ident : $value:expr),* $(,)* })*),+ $(,)*) => {{
+        let mut message = $crate::Message::new();
+        $(
+            message.$kind($arg);
+            $($( message.$opt($value); )*)*
+        )+
+        message.run(&$spidev)
+    }};
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Message, Step};
+    use spidevioctl::InvalidTransfer;
+    use test::test_device;
+    use Spidev;
+
+    #[test]
+    fn test_message_steps() {
+        let cmd = [0x01, 0x02];
+        let mut message = Message::new();
+        message.write(&cmd).speed_hz(1_000_000).read(4).delay_usecs(10).cs_change(true);
+        assert_eq!(message.steps,
+                   vec![Step {
+                            tx_buf: Some(&cmd),
+                            len: 2,
+                            speed_hz: 1_000_000,
+                            ..Default::default()
+                        },
+                        Step {
+                            len: 4,
+                            reads: true,
+                            delay_usecs: 10,
+                            cs_change: true,
+                            ..Default::default()
+                        }]);
+    }
+
+    #[test]
+    fn test_keep_cs_asserted() {
+        let mut message = Message::new();
+        message.write(&[0x01]).cs_change(true).read(2);
+        let mut rx_bufs = message.rx_bufs();
+        let cs_changes = |message: &Message, rx_bufs: &mut [Vec<u8>]| -> Vec<u8> {
+            message.transfers(rx_bufs).unwrap().iter().map(|t| t.cs_change).collect()
+        };
+        assert_eq!(cs_changes(&message, &mut rx_bufs), [1, 0]);
+        message.keep_cs_asserted(true);
+        assert_eq!(cs_changes(&message, &mut rx_bufs), [1, 1]);
+        // only the final transfer is affected
+        message.keep_cs_asserted(false);
+        assert_eq!(cs_changes(&message, &mut rx_bufs), [1, 0]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_message_option_without_transfer() {
+        Message::new().speed_hz(1_000_000);
+    }
+
+    #[test]
+    fn test_message_invalid_transfer() {
+        let spidev = Spidev::open("/dev/null").unwrap();
+        let err = Message::new()
+                      .write(&[0x01])
+                      .read(2)
+                      .read(2)
+                      .bits_per_word(40)
+                      .read(2)
+                      .run(&spidev)
+                      .unwrap_err();
+        let invalid = err.get_ref().unwrap().downcast_ref::<InvalidTransfer>().unwrap();
+        assert_eq!(invalid.index, 2);
+    }
+
+    #[test]
+    fn test_transfer_macro_error() {
+        let spidev = Spidev::open("/dev/null").unwrap();
+        assert!(transfer!(spidev, write &[0x01], read 2).is_err());
+    }
+
+    #[test]
+    #[ignore]
+    fn test_transfer_macro_two() {
+        let spidev = test_device();
+        let responses = transfer!(spidev, write &[0x9f], read 3 => { speed_hz: 100_000 })
+                            .unwrap();
+        // nothing is shifted out while reading, so loopback reads zeroes
+        assert_eq!(responses, vec![vec![0; 3]]);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_transfer_macro_three() {
+        let spidev = test_device();
+        let data = [0x01, 0x02, 0x03];
+        let responses = transfer!(spidev,
+                                  write &[0x0b],
+                                  read_write &data => { delay_usecs: 5, cs_change: false },
+                                  read 2)
+                            .unwrap();
+        assert_eq!(responses, vec![data.to_vec(), vec![0; 2]]);
+    }
+}
@@ -0,0 +1,234 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Locking transfer buffers into memory for real-time use
+//!
+//! A page fault while a buffer is being copied in or out of the kernel
+//! can stall a control loop for far longer than the transfer itself.
+//! `PinnedBuffer` keeps a buffer locked for its whole lifetime, and
+//! `Spidev::transfer_pinned` locks a transfer's buffers just for one
+//! transfer.  Requires the `mlock` feature.
+//!
+//! # Limits
+//!
+//! Locked memory counts against `RLIMIT_MEMLOCK`, which is often only a
+//! few megabytes, or 64 KiB on older systems, for unprivileged
+//! processes.  Locking beyond it fails with `ENOMEM` (or `EPERM` if the
+//! limit is zero); raise it with `ulimit -l` or `setrlimit`, or grant
+//! the process `CAP_IPC_LOCK`.
+//!
+//! Locks apply to whole pages and don't nest, so unlocking a buffer
+//! after a transfer also unlocks any other memory sharing its pages.
+//! Buffers reused on every cycle are best kept in a `PinnedBuffer`.
+
+use std::io;
+use std::ops::{Deref, DerefMut};
+use libc;
+use spidevioctl::SpidevTransfer;
+
+/// The system calls used to lock memory, replaceable for tests
+pub(crate) trait MemoryLock {
+    fn lock(&self, addr: *const u8, len: usize) -> io::Result<()>;
+    fn unlock(&self, addr: *const u8, len: usize) -> io::Result<()>;
+}
+
+/// `mlock` and `munlock`
+pub(crate) struct Syscalls;
+
+impl MemoryLock for Syscalls {
+    fn lock(&self, addr: *const u8, len: usize) -> io::Result<()> {
+        if unsafe { libc::mlock(addr as *const libc::c_void, len) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn unlock(&self, addr: *const u8, len: usize) -> io::Result<()> {
+        if unsafe { libc::munlock(addr as *const libc::c_void, len) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// The address and length of each distinct non-empty buffer of
+/// `transfers`
+fn buffers(transfers: &[SpidevTransfer]) -> Vec<(*const u8, usize)> {
+    let mut bufs = Vec::new();
+    for transfer in transfers {
+        for buf in &[transfer.tx_slice(), transfer.rx_slice()] {
+            let buf = (buf.as_ptr(), buf.len());
+            // an in-place transfer has the same buffer twice
+            if buf.1 != 0 && !bufs.contains(&buf) {
+                bufs.push(buf);
+            }
+        }
+    }
+    bufs
+}
+
+/// Run `f` on `transfers` with their buffers locked into memory
+///
+/// If a buffer can't be locked those already locked are unlocked again
+/// and `f` is not run.
+pub(crate) fn with_locked<L, F, T>(lock: &L, transfers: &mut [SpidevTransfer], f: F) -> io::Result<T>
+    where L: MemoryLock,
+          F: FnOnce(&mut [SpidevTransfer]) -> io::Result<T>
+{
+    let bufs = buffers(transfers);
+    for (i, &(addr, len)) in bufs.iter().enumerate() {
+        if let Err(err) = lock.lock(addr, len) {
+            for &(addr, len) in &bufs[..i] {
+                let _ = lock.unlock(addr, len);
+            }
+            return Err(err);
+        }
+    }
+    let result = f(transfers);
+    for &(addr, len) in &bufs {
+        // a range that was locked can always be unlocked
+        let _ = lock.unlock(addr, len);
+    }
+    result
+}
+
+/// A buffer that stays locked into memory until it is dropped
+///
+/// Use it as the transmit or receive buffer of transfers that must not
+/// page fault; see the module documentation for the limits involved.
+#[derive(Debug)]
+pub struct PinnedBuffer {
+    buf: Vec<u8>,
+}
+
+impl PinnedBuffer {
+    /// Allocate a zeroed buffer of `len` bytes and lock it
+    pub fn new(len: usize) -> io::Result<PinnedBuffer> {
+        PinnedBuffer::with_lock(&Syscalls, vec![0; len])
+    }
+
+    fn with_lock<L: MemoryLock>(lock: &L, buf: Vec<u8>) -> io::Result<PinnedBuffer> {
+        if !buf.is_empty() {
+            lock.lock(buf.as_ptr(), buf.len())?;
+        }
+        Ok(PinnedBuffer { buf })
+    }
+}
+
+impl Deref for PinnedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for PinnedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Drop for PinnedBuffer {
+    fn drop(&mut self) {
+        if !self.buf.is_empty() {
+            let _ = Syscalls.unlock(self.buf.as_ptr(), self.buf.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::io;
+    use libc;
+    use super::{with_locked, MemoryLock, PinnedBuffer};
+    use SpidevTransfer;
+
+    /// Records the address and length of each call
+    #[derive(Default)]
+    struct MockLock {
+        calls: RefCell<Vec<(&'static str, usize, usize)>>,
+        fail_after: Option<usize>,
+    }
+
+    impl MockLock {
+        fn record(&self, name: &'static str, addr: *const u8, len: usize) -> io::Result<()> {
+            let mut calls = self.calls.borrow_mut();
+            if name == "mlock" && Some(calls.len()) == self.fail_after {
+                return Err(io::Error::from_raw_os_error(libc::ENOMEM));
+            }
+            calls.push((name, addr as usize, len));
+            Ok(())
+        }
+    }
+
+    impl MemoryLock for MockLock {
+        fn lock(&self, addr: *const u8, len: usize) -> io::Result<()> {
+            self.record("mlock", addr, len)
+        }
+
+        fn unlock(&self, addr: *const u8, len: usize) -> io::Result<()> {
+            self.record("munlock", addr, len)
+        }
+    }
+
+    #[test]
+    fn test_with_locked() {
+        let lock = MockLock::default();
+        let tx_buf = [0; 4];
+        let mut rx_buf = [0; 4];
+        let mut in_place = [0; 2];
+        let (tx, rx, other) = (tx_buf.as_ptr() as usize,
+                               rx_buf.as_ptr() as usize,
+                               in_place.as_ptr() as usize);
+        let mut transfers = [SpidevTransfer::read_write(&tx_buf, &mut rx_buf),
+                             SpidevTransfer::empty(),
+                             SpidevTransfer::in_place(&mut in_place)];
+        let value = with_locked(&lock, &mut transfers, |transfers| {
+                        assert_eq!(transfers.len(), 3);
+                        assert_eq!(lock.calls.borrow().len(), 3);
+                        Ok(7)
+                    })
+                        .unwrap();
+        assert_eq!(value, 7);
+        assert_eq!(*lock.calls.borrow(),
+                   [("mlock", tx, 4),
+                    ("mlock", rx, 4),
+                    ("mlock", other, 2),
+                    ("munlock", tx, 4),
+                    ("munlock", rx, 4),
+                    ("munlock", other, 2)]);
+    }
+
+    #[test]
+    fn test_with_locked_failure() {
+        let lock = MockLock {
+            fail_after: Some(1),
+            ..Default::default()
+        };
+        let tx_buf = [0; 4];
+        let mut rx_buf = [0; 4];
+        let tx = tx_buf.as_ptr() as usize;
+        let mut transfers = [SpidevTransfer::read_write(&tx_buf, &mut rx_buf)];
+        let result: io::Result<()> = with_locked(&lock, &mut transfers, |_| panic!("must not run"));
+        assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::ENOMEM));
+        // the receive buffer failed to lock, so only the transmit buffer
+        // is unlocked
+        assert_eq!(*lock.calls.borrow(), [("mlock", tx, 4), ("munlock", tx, 4)]);
+    }
+
+    #[test]
+    fn test_pinned_buffer_locks() {
+        let lock = MockLock::default();
+        let buf = PinnedBuffer::with_lock(&lock, vec![0; 64]).unwrap();
+        assert_eq!(*lock.calls.borrow(), [("mlock", buf.as_ptr() as usize, 64)]);
+        assert!(PinnedBuffer::with_lock(&lock, Vec::new()).unwrap().is_empty());
+        assert_eq!(lock.calls.borrow().len(), 1);
+    }
+}
@@ -0,0 +1,124 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Waiting for a device to accept a transfer
+
+use std::io;
+use std::os::unix::prelude::*;
+use std::time::{Duration, Instant};
+use libc;
+
+/// The `poll` timeout for `timeout`, rounded up to whole milliseconds
+fn poll_timeout(timeout: Duration) -> libc::c_int {
+    let millis = (timeout.as_nanos() + 999_999) / 1_000_000;
+    if millis > libc::c_int::MAX as u128 {
+        libc::c_int::MAX
+    } else {
+        millis as libc::c_int
+    }
+}
+
+pub(crate) fn wait_ready_with<P>(fd: RawFd, timeout: Duration, mut poll: P) -> io::Result<()>
+    where P: FnMut(&mut libc::pollfd, libc::c_int) -> io::Result<libc::c_int>
+{
+    let deadline = Instant::now() + timeout;
+    loop {
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLOUT,
+            revents: 0,
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match poll(&mut pollfd, poll_timeout(remaining)) {
+            Ok(0) => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                          "device did not become ready"))
+            }
+            Ok(_) => {
+                if pollfd.revents & libc::POLLOUT != 0 {
+                    return Ok(());
+                }
+                // POLLERR, POLLHUP or POLLNVAL
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                          "device reported an error while waiting"));
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Wait until the device open on `fd` can accept a transfer
+///
+/// This polls `fd` for writability, failing with `TimedOut` if that
+/// doesn't happen within `timeout`.  Whether it waits for anything
+/// depends on the driver: the spidev driver itself doesn't implement
+/// `poll`, so for a plain spidev node the kernel reports the device as
+/// ready straight away, even with `SPI_READY` set.  It is useful for
+/// drivers providing a spidev-compatible node that do signal readiness,
+/// and for writing code that stays correct with them.
+pub fn wait_ready(fd: RawFd, timeout: Duration) -> io::Result<()> {
+    wait_ready_with(fd, timeout, |pollfd, timeout| {
+        match unsafe { libc::poll(pollfd, 1, timeout) } {
+            -1 => Err(io::Error::last_os_error()),
+            ready => Ok(ready),
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::time::Duration;
+    use libc;
+    use super::{poll_timeout, wait_ready_with};
+
+    #[test]
+    fn test_poll_timeout() {
+        assert_eq!(poll_timeout(Duration::from_millis(0)), 0);
+        assert_eq!(poll_timeout(Duration::from_micros(1)), 1);
+        assert_eq!(poll_timeout(Duration::from_millis(250)), 250);
+        assert_eq!(poll_timeout(Duration::from_secs(u64::MAX)), libc::c_int::MAX);
+    }
+
+    #[test]
+    fn test_wait_ready() {
+        let timeout = Duration::from_millis(10);
+        let ready = wait_ready_with(3, timeout, |pollfd, _| {
+            assert_eq!(pollfd.fd, 3);
+            pollfd.revents = libc::POLLOUT;
+            Ok(1)
+        });
+        assert!(ready.is_ok());
+
+        let err = wait_ready_with(3, timeout, |_, _| Ok(0)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        let err = wait_ready_with(3, timeout, |pollfd, _| {
+                      pollfd.revents = libc::POLLERR;
+                      Ok(1)
+                  })
+                      .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+    }
+
+    #[test]
+    fn test_wait_ready_interrupted() {
+        let mut calls = 0;
+        let ready = wait_ready_with(3, Duration::from_millis(10), |pollfd, _| {
+            calls += 1;
+            if calls == 1 {
+                return Err(io::Error::from_raw_os_error(libc::EINTR));
+            }
+            pollfd.revents = libc::POLLOUT;
+            Ok(1)
+        });
+        assert!(ready.is_ok());
+        assert_eq!(calls, 2);
+    }
+}
@@ -0,0 +1,336 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Recording transfers for later inspection
+//!
+//! `RecordingSpidev` wraps a `Spidev` and keeps a transcript of every
+//! message sent through it, which can be dumped as JSON and compared
+//! across runs when chasing intermittent device behavior.
+
+use std::fmt::Write as FmtWrite;
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::prelude::*;
+use std::time::{Duration, Instant};
+use spidevioctl::{self, SpidevTransfer};
+use Spidev;
+
+/// A single transfer as it was performed
+///
+/// The override fields are copied from the `SpidevTransfer`, so as there
+/// a value of zero means the device default was used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedTransfer {
+    /// The bytes sent, empty if the transfer had no transmit buffer
+    pub tx: Vec<u8>,
+    /// The bytes received, empty if the transfer had no receive buffer
+    pub rx: Vec<u8>,
+    pub speed_hz: u32,
+    pub delay_usecs: u16,
+    pub bits_per_word: u8,
+    pub cs_change: bool,
+    pub tx_nbits: u8,
+    pub rx_nbits: u8,
+    pub word_delay_usecs: u8,
+}
+
+impl RecordedTransfer {
+    fn new(transfer: &SpidevTransfer) -> Self {
+        RecordedTransfer {
+            tx: transfer.tx_slice().to_vec(),
+            rx: transfer.rx_slice().to_vec(),
+            speed_hz: transfer.speed_hz,
+            delay_usecs: transfer.delay_usecs,
+            bits_per_word: transfer.bits_per_word,
+            cs_change: transfer.cs_change != 0,
+            tx_nbits: transfer.tx_nbits,
+            rx_nbits: transfer.rx_nbits,
+            word_delay_usecs: transfer.word_delay_usecs,
+        }
+    }
+}
+
+/// One `SPI_IOC_MESSAGE` ioctl and its outcome
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedMessage {
+    pub transfers: Vec<RecordedTransfer>,
+    /// The number of bytes transferred, or a description of the error
+    pub result: Result<u32, String>,
+    /// How long the ioctl took
+    pub elapsed: Duration,
+}
+
+/// A `Spidev` that records every message performed through it
+///
+/// Only messages sent with `transfer` and `transfer_multiple` are
+/// recorded; the underlying device is available through `spidev` for
+/// configuration.
+pub struct RecordingSpidev {
+    spidev: Spidev,
+    transcript: Vec<RecordedMessage>,
+}
+
+impl RecordingSpidev {
+    /// Start recording the messages sent to `spidev`
+    pub fn new(spidev: Spidev) -> Self {
+        RecordingSpidev {
+            spidev,
+            transcript: Vec::new(),
+        }
+    }
+
+    /// The wrapped device
+    pub fn spidev(&mut self) -> &mut Spidev {
+        &mut self.spidev
+    }
+
+    /// Stop recording, returning the wrapped device
+    pub fn into_inner(self) -> Spidev {
+        self.spidev
+    }
+
+    /// Perform a single transfer, recording it
+    pub fn transfer(&mut self, transfer: &mut SpidevTransfer) -> io::Result<()> {
+        self.transfer_multiple(std::slice::from_mut(transfer))
+    }
+
+    /// Perform multiple transfers as one message, recording it
+    ///
+    /// Messages that fail are recorded too, along with their error.
+    pub fn transfer_multiple(&mut self, transfers: &mut [SpidevTransfer]) -> io::Result<()> {
+        let fd = self.spidev.as_raw_fd();
+        self.record_with(transfers, |transfers| spidevioctl::transfer_multiple(fd, transfers))
+    }
+
+    /// Prepare and perform `transfers` with `run`, recording the message
+    fn record_with<F>(&mut self, transfers: &mut [SpidevTransfer], run: F) -> io::Result<()>
+        where F: FnOnce(&mut [SpidevTransfer]) -> io::Result<u32>
+    {
+        let start = Instant::now();
+        let result = self.spidev.prepare(transfers).and_then(|()| run(transfers));
+        let elapsed = start.elapsed();
+        self.transcript.push(RecordedMessage {
+            transfers: transfers.iter().map(RecordedTransfer::new).collect(),
+            result: match result {
+                Ok(bytes) => Ok(bytes),
+                Err(ref err) => Err(err.to_string()),
+            },
+            elapsed,
+        });
+        result.map(|_| ())
+    }
+
+    /// The messages recorded so far, oldest first
+    pub fn transcript(&self) -> &[RecordedMessage] {
+        &self.transcript
+    }
+
+    /// Discard the messages recorded so far
+    pub fn clear(&mut self) {
+        self.transcript.clear();
+    }
+
+    /// Serialize the transcript as JSON
+    ///
+    /// The transcript is an array of messages, each an object with
+    /// `transfers`, `elapsed_us` and either `bytes` or `error`.  Buffers
+    /// are arrays of byte values.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("[");
+        for (i, message) in self.transcript.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            write_message(&mut json, message);
+        }
+        json.push(']');
+        json
+    }
+
+    /// Write the transcript as JSON; see `to_json`
+    pub fn write_json<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(self.to_json().as_bytes())
+    }
+}
+
+// Writing to a String cannot fail, so the results of write! are ignored
+// throughout.
+
+fn write_message(json: &mut String, message: &RecordedMessage) {
+    json.push_str("{\"transfers\":[");
+    for (i, transfer) in message.transfers.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        write_transfer(json, transfer);
+    }
+    let _ = write!(json, "],\"elapsed_us\":{},", message.elapsed.as_micros());
+    match message.result {
+        Ok(bytes) => {
+            let _ = write!(json, "\"bytes\":{}", bytes);
+        }
+        Err(ref err) => {
+            json.push_str("\"error\":");
+            write_string(json, err);
+        }
+    }
+    json.push('}');
+}
+
+fn write_transfer(json: &mut String, transfer: &RecordedTransfer) {
+    json.push_str("{\"tx\":");
+    write_bytes(json, &transfer.tx);
+    json.push_str(",\"rx\":");
+    write_bytes(json, &transfer.rx);
+    let _ = write!(json,
+                   ",\"speed_hz\":{},\"delay_usecs\":{},\"bits_per_word\":{},\
+                    \"cs_change\":{},\"tx_nbits\":{},\"rx_nbits\":{},\"word_delay_usecs\":{}}}",
+                   transfer.speed_hz,
+                   transfer.delay_usecs,
+                   transfer.bits_per_word,
+                   transfer.cs_change,
+                   transfer.tx_nbits,
+                   transfer.rx_nbits,
+                   transfer.word_delay_usecs);
+}
+
+fn write_bytes(json: &mut String, bytes: &[u8]) {
+    json.push('[');
+    for (i, byte) in bytes.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let _ = write!(json, "{}", byte);
+    }
+    json.push(']');
+}
+
+fn write_string(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::time::Duration;
+    use super::{write_string, RecordedMessage, RecordedTransfer, RecordingSpidev};
+    use test::test_device;
+    use {Spidev, SpidevTransfer};
+
+    /// Stand in for a loopback connection, returning the bytes transferred
+    fn loopback(transfers: &mut [SpidevTransfer]) -> io::Result<u32> {
+        let mut bytes = 0;
+        for transfer in transfers {
+            let tx_buf = transfer.tx_slice().to_vec();
+            let rx_buf = transfer.rx_slice_mut();
+            if !rx_buf.is_empty() {
+                rx_buf.copy_from_slice(&tx_buf);
+            }
+            bytes += tx_buf.len() as u32;
+        }
+        Ok(bytes)
+    }
+
+    #[test]
+    fn test_records_transfers() {
+        let mut recorder = RecordingSpidev::new(Spidev::open("/dev/null").unwrap());
+        recorder.spidev().set_session_bits_per_word(8);
+        let tx_buf = [0x01, 0x02];
+        let mut rx_buf = [0xaa; 2];
+        recorder.record_with(&mut [SpidevTransfer::write(&tx_buf)], loopback).unwrap();
+        let mut transfer = SpidevTransfer::read_write(&tx_buf, &mut rx_buf).cs_change(true);
+        transfer.speed_hz = 500_000;
+        recorder.record_with(std::slice::from_mut(&mut transfer), loopback).unwrap();
+
+        let transcript = recorder.transcript();
+        assert_eq!(transcript.len(), 2);
+        assert_eq!(transcript[0].transfers[0].tx, tx_buf);
+        assert!(transcript[0].transfers[0].rx.is_empty());
+        assert_eq!(transcript[0].result, Ok(2));
+        assert_eq!(transcript[1].transfers[0].tx, tx_buf);
+        assert_eq!(transcript[1].transfers[0].rx, tx_buf);
+        assert_eq!(transcript[1].transfers[0].speed_hz, 500_000);
+        assert!(transcript[1].transfers[0].cs_change);
+        // the session defaults are filled in before recording
+        assert_eq!(transcript[1].transfers[0].bits_per_word, 8);
+        assert_eq!(transcript[1].result, Ok(2));
+
+        recorder.clear();
+        assert_eq!(recorder.to_json(), "[]");
+    }
+
+    #[test]
+    fn test_records_failed_transfers() {
+        // the ioctl fails on /dev/null, leaving the receive buffer as it was
+        let mut recorder = RecordingSpidev::new(Spidev::open("/dev/null").unwrap());
+        let tx_buf = [0x01, 0x02];
+        let mut rx_buf = [0xaa; 2];
+        assert!(recorder.transfer(&mut SpidevTransfer::read_write(&tx_buf, &mut rx_buf)).is_err());
+        let transcript = recorder.transcript();
+        assert_eq!(transcript[0].transfers[0].rx, [0xaa; 2]);
+        assert!(transcript[0].result.is_err());
+    }
+
+    #[test]
+    fn test_to_json() {
+        let mut recorder = RecordingSpidev::new(Spidev::open("/dev/null").unwrap());
+        recorder.transcript.push(RecordedMessage {
+            transfers: vec![RecordedTransfer {
+                                tx: vec![1, 2],
+                                rx: vec![3, 4],
+                                speed_hz: 0,
+                                delay_usecs: 5,
+                                bits_per_word: 8,
+                                cs_change: false,
+                                tx_nbits: 0,
+                                rx_nbits: 0,
+                                word_delay_usecs: 0,
+                            }],
+            result: Ok(2),
+            elapsed: Duration::from_micros(42),
+        });
+        assert_eq!(recorder.to_json(),
+                   "[{\"transfers\":[{\"tx\":[1,2],\"rx\":[3,4],\"speed_hz\":0,\"delay_usecs\":5,\
+                    \"bits_per_word\":8,\"cs_change\":false,\"tx_nbits\":0,\"rx_nbits\":0,\
+                    \"word_delay_usecs\":0}],\"elapsed_us\":42,\"bytes\":2}]");
+    }
+
+    #[test]
+    fn test_write_string_escapes() {
+        let mut json = String::new();
+        write_string(&mut json, "a \"b\"\\\n\u{1}");
+        assert_eq!(json, "\"a \\\"b\\\"\\\\\\n\\u0001\"");
+    }
+
+    #[test]
+    #[ignore]
+    fn test_records_transfers_device() {
+        let mut recorder = RecordingSpidev::new(test_device());
+        let tx_buf = [0x5a; 4];
+        let mut rx_buf = [0; 4];
+        recorder.transfer(&mut SpidevTransfer::read_write(&tx_buf, &mut rx_buf)).unwrap();
+        recorder.transfer(&mut SpidevTransfer::write(&tx_buf)).unwrap();
+        let transcript = recorder.transcript();
+        assert_eq!(transcript.len(), 2);
+        assert_eq!(transcript[0].result, Ok(4));
+        // requires MOSI to be looped back to MISO
+        assert_eq!(transcript[0].transfers[0].rx, tx_buf);
+    }
+}
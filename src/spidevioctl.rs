@@ -8,11 +8,18 @@
 
 #![allow(dead_code)]
 
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
 use std::io;
 use std::marker::PhantomData;
 use std::mem;
+use std::ptr;
+use std::slice;
+use std::time::{Duration, Instant};
 use std::os::unix::prelude::*;
 use super::SpiModeFlags;
+use words::{WordSize, WordsU16, WordsU32};
 
 fn from_nix_error(err: ::nix::Error) -> io::Error {
     io::Error::from_raw_os_error(err.errno() as i32)
@@ -26,6 +33,32 @@ fn from_nix_result<T>(res: ::nix::Result<T>) -> io::Result<T> {
 }
 
 
+/// A delay after a transfer, in microseconds
+///
+/// Used for `spi_ioc_transfer::delay_usecs`; see `WordDelay` for the
+/// delay between words within a transfer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Microseconds(pub u16);
+
+impl From<u16> for Microseconds {
+    fn from(usecs: u16) -> Microseconds {
+        Microseconds(usecs)
+    }
+}
+
+/// A delay between words within a transfer, in microseconds
+///
+/// Used for `spi_ioc_transfer::word_delay_usecs`.  Only controllers
+/// with explicit support honor it; others silently ignore it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WordDelay(pub u8);
+
+impl From<u8> for WordDelay {
+    fn from(usecs: u8) -> WordDelay {
+        WordDelay(usecs)
+    }
+}
+
 /// Structure that is used when performing communication
 /// with the kernel.
 ///
@@ -42,6 +75,9 @@ fn from_nix_result<T>(res: ::nix::Result<T>) -> io::Result<T> {
 /// @delay_usecs: If nonzero, how long to delay after the last bit transfer
 ///      before optionally deselecting the device before the next transfer.
 /// @cs_change: True to deselect device before starting the next transfer.
+/// @word_delay_usecs: If nonzero, how long to wait between words within one
+///      transfer. This property needs explicit support in the SPI controller,
+///      otherwise it is silently ignored.
 ///
 /// This structure is mapped directly to the kernel spi_transfer structure;
 /// the fields have the same meanings, except of course that the pointers
@@ -64,6 +100,16 @@ fn from_nix_result<T>(res: ::nix::Result<T>) -> io::Result<T> {
 /// could send a different nine bit command (re-selecting the chip), and the
 /// last transfer might write some register values.
 /// ```
+///
+/// # Chip select timing
+///
+/// Userspace only controls chip select timing through `delay_usecs`
+/// (how long to wait after the last bit before chip select may be
+/// released), `word_delay_usecs` and `cs_change`.  The setup, hold and
+/// inactive delays that the kernel applies around chip select are not
+/// part of this structure; they are configured for the SPI device in the
+/// device tree (`spi-cs-setup-delay-ns`, `spi-cs-hold-delay-ns` and
+/// `spi-cs-inactive-delay-ns`) and cannot be changed through spidev.
 #[allow(non_camel_case_types)]
 #[derive(Debug, Default)]
 #[repr(C)]
@@ -77,45 +123,436 @@ pub struct spi_ioc_transfer<'a, 'b> {
     pub delay_usecs: u16,
     pub bits_per_word: u8,
     pub cs_change: u8,
-    pub pad: u32,
+    pub tx_nbits: u8,
+    pub rx_nbits: u8,
+    pub word_delay_usecs: u8,
+    pub pad: u8,
 
     tx_buf_ref: PhantomData<&'a [u8]>,
     rx_buf_ref: PhantomData<&'b mut [u8]>,
 }
 
+// The kernel's struct is 32 bytes in both its original layout, which
+// ended in a 32-bit `pad`, and the current one, which splits that into
+// `tx_nbits`, `rx_nbits`, `word_delay_usecs` and `pad`.  The ioctl
+// request number encodes this size, so a change to it would make every
+// transfer fail or, worse, be misread by an older kernel.  This fails to
+// compile if the size drifts; the field offsets are checked by
+// `test_transfer_layout`, as they can't be computed in a constant on the
+// minimum supported rustc.
+const _: [(); 32] = [(); mem::size_of::<spi_ioc_transfer<'static, 'static>>()];
+
 impl<'a, 'b> spi_ioc_transfer<'a, 'b> {
+    /// A transfer that clocks no data
+    ///
+    /// The kernel still honors `delay_usecs` and `cs_change` for a
+    /// zero-length transfer, so this can be used to insert a delay into a
+    /// message or to toggle chip select.  The same is true of the other
+    /// constructors when given empty buffers, which are passed to the
+    /// kernel as null pointers.
+    pub fn empty() -> Self {
+        Default::default()
+    }
+
+    /// A transfer that clocks `len` bytes of zeroes, discarding the response
+    ///
+    /// Use this to give a device extra clock cycles between the phases of
+    /// a protocol.  Neither buffer is passed to the kernel: with a null
+    /// `tx_buf` the SPI core shifts out zeroes, and with a null `rx_buf`
+    /// whatever is clocked in is dropped, so no memory is needed however
+    /// long the transfer.
+    pub fn nop(len: u32) -> Self {
+        spi_ioc_transfer {
+            len,
+            ..Default::default()
+        }
+    }
+
+    /// A buffer longer than `u32::MAX` bytes gives a transfer of
+    /// `u32::MAX` bytes, rather than wrapping to a short length, which
+    /// the kernel then rejects as too long.  Use `try_read` to get an
+    /// error before the transfer is submitted.
     pub fn read(buff: &'b mut [u8]) -> Self {
         spi_ioc_transfer {
-            rx_buf: buff.as_ptr() as *const () as usize as u64,
-            len: buff.len() as u32,
+            rx_buf: rx_addr(buff),
+            len: clamped_len(buff.len()),
             ..Default::default()
         }
     }
 
+    /// A buffer longer than `u32::MAX` bytes is clamped as for `read`;
+    /// see `try_write`.
     pub fn write(buff: &'a [u8]) -> Self {
         spi_ioc_transfer {
-            tx_buf: buff.as_ptr() as *const () as usize as u64,
-            len: buff.len() as u32,
+            tx_buf: tx_addr(buff),
+            len: clamped_len(buff.len()),
             ..Default::default()
         }
     }
 
     /// The `tx_buf` and `rx_buf` must be the same length.
+    ///
+    /// Buffers longer than `u32::MAX` bytes are clamped as for `read`;
+    /// see `try_read_write`.
     pub fn read_write(tx_buf: &'a [u8], rx_buf: &'b mut [u8]) -> Self {
         assert_eq!(tx_buf.len(), rx_buf.len());
         spi_ioc_transfer {
-            rx_buf: rx_buf.as_ptr() as *const () as usize as u64,
-            tx_buf: tx_buf.as_ptr() as *const () as usize as u64,
-            len: tx_buf.len() as u32,
+            rx_buf: rx_addr(rx_buf),
+            tx_buf: tx_addr(tx_buf),
+            len: clamped_len(tx_buf.len()),
             ..Default::default()
         }
     }
+
+    /// Like `read`, but returns an error if the buffer length does not
+    /// fit in the kernel's `u32` length field.
+    pub fn try_read(buff: &'b mut [u8]) -> io::Result<Self> {
+        Ok(spi_ioc_transfer {
+            rx_buf: rx_addr(buff),
+            len: transfer_len(buff.len())?,
+            ..Default::default()
+        })
+    }
+
+    /// Like `write`, but returns an error if the buffer length does not
+    /// fit in the kernel's `u32` length field.
+    pub fn try_write(buff: &'a [u8]) -> io::Result<Self> {
+        Ok(spi_ioc_transfer {
+            tx_buf: tx_addr(buff),
+            len: transfer_len(buff.len())?,
+            ..Default::default()
+        })
+    }
+
+    /// Like `read_write`, but returns an error if the buffer length does
+    /// not fit in the kernel's `u32` length field.
+    pub fn try_read_write(tx_buf: &'a [u8], rx_buf: &'b mut [u8]) -> io::Result<Self> {
+        assert_eq!(tx_buf.len(), rx_buf.len());
+        Ok(spi_ioc_transfer {
+            rx_buf: rx_addr(rx_buf),
+            tx_buf: tx_addr(tx_buf),
+            len: transfer_len(tx_buf.len())?,
+            ..Default::default()
+        })
+    }
+
+    /// Delay after the last bit of this transfer before optionally
+    /// deselecting the device
+    pub fn delay_usecs<D: Into<Microseconds>>(mut self, delay: D) -> Self {
+        self.delay_usecs = delay.into().0;
+        self
+    }
+
+    /// Delay between the words of this transfer
+    pub fn word_delay_usecs<D: Into<WordDelay>>(mut self, delay: D) -> Self {
+        self.word_delay_usecs = delay.into().0;
+        self
+    }
+
+    /// Override the word size for this transfer
+    ///
+    /// The buffers should hold a whole number of `size.bytes()` words.
+    pub fn word_size(mut self, size: WordSize) -> Self {
+        self.bits_per_word = size.bits();
+        self
+    }
+
+    /// Deselect the device after this transfer, before the next one
+    ///
+    /// On the final transfer of a message this has the opposite effect:
+    /// chip select is left asserted after the message completes.
+    pub fn cs_change(mut self, cs_change: bool) -> Self {
+        self.cs_change = cs_change as u8;
+        self
+    }
+
+    /// Zero the receive buffer, if this transfer has one
+    ///
+    /// When reusing a buffer across transfers, clearing it first means
+    /// any bytes left untouched by a short transfer read as zero rather
+    /// than as stale data from the previous one.  This is opt-in so the
+    /// common path does not pay for the extra write.
+    pub fn clear_rx(&mut self) {
+        if self.rx_buf != 0 {
+            // rx_buf was derived from the `&'b mut [u8]` this transfer
+            // borrows, which is valid for `len` bytes
+            unsafe {
+                ptr::write_bytes(self.rx_buf as usize as *mut u8, 0, self.len as usize);
+            }
+        }
+    }
+
+    /// The receive buffer, for tests standing in for the kernel
+    #[cfg(test)]
+    pub(crate) fn rx_slice_mut(&mut self) -> &mut [u8] {
+        if self.rx_buf == 0 {
+            return &mut [];
+        }
+        // as for `clear_rx`
+        unsafe { slice::from_raw_parts_mut(self.rx_buf as usize as *mut u8, self.len as usize) }
+    }
+
+    /// The transmit buffer, or an empty slice if this transfer has none
+    pub(crate) fn tx_slice(&self) -> &[u8] {
+        if self.tx_buf == 0 {
+            return &[];
+        }
+        // tx_buf was derived from the `&'a [u8]` this transfer borrows
+        unsafe { slice::from_raw_parts(self.tx_buf as usize as *const u8, self.len as usize) }
+    }
+
+    /// The receive buffer, or an empty slice if this transfer has none
+    pub(crate) fn rx_slice(&self) -> &[u8] {
+        if self.rx_buf == 0 {
+            return &[];
+        }
+        // as for `clear_rx`, rx_buf points to `len` bytes borrowed by
+        // this transfer
+        unsafe { slice::from_raw_parts(self.rx_buf as usize as *const u8, self.len as usize) }
+    }
+
+    /// Iterate over the receive buffer as 16-bit words
+    ///
+    /// Use this after a transfer with `bits_per_word` of 9 to 16, for
+    /// which the kernel stores each word in two native-endian bytes.
+    pub fn rx_words_u16(&self) -> WordsU16<'_> {
+        WordsU16::new(self.rx_slice())
+    }
+
+    /// Iterate over the receive buffer as 32-bit words
+    ///
+    /// Use this after a transfer with `bits_per_word` of 17 to 32, for
+    /// which the kernel stores each word in four native-endian bytes.
+    pub fn rx_words_u32(&self) -> WordsU32<'_> {
+        WordsU32::new(self.rx_slice())
+    }
+
+    /// Approximate how long this transfer will take on the wire
+    ///
+    /// `bits_per_word` and `speed_hz` are the device defaults, which are
+    /// used unless this transfer overrides them.  Any `word_delay_usecs`
+    /// between words and `delay_usecs` after the transfer are included.
+    /// See `estimated_duration`, which also gives the cases that return
+    /// `None`.
+    pub fn estimated_duration(&self, bits_per_word: u8, speed_hz: u32) -> Option<Duration> {
+        let bits_per_word = if self.bits_per_word != 0 {
+            self.bits_per_word
+        } else {
+            bits_per_word
+        };
+        let speed_hz = if self.speed_hz != 0 {
+            self.speed_hz
+        } else {
+            speed_hz
+        };
+        let words = word_count(self.len as usize, bits_per_word);
+        let delays = u64::from(self.word_delay_usecs)
+                         .checked_mul(words.saturating_sub(1))?
+                         .checked_add(u64::from(self.delay_usecs))?;
+        estimated_duration(self.len as usize, bits_per_word, speed_hz)?
+            .checked_add(Duration::from_micros(delays))
+    }
+}
+
+/// Approximate how long a transfer of `len_bytes` takes on the wire
+///
+/// Words of up to 8, 16 and 32 bits occupy 1, 2 and 4 bytes of the
+/// buffer respectively and a `bits_per_word` of zero means eight bits,
+/// as with the kernel.  The estimate only covers clocking the data; it
+/// does not include system call overhead or delays requested between
+/// transfers.
+///
+/// Returns `None` if `speed_hz` is zero or the duration does not fit in
+/// a `Duration`.
+pub fn estimated_duration(len_bytes: usize, bits_per_word: u8, speed_hz: u32) -> Option<Duration> {
+    if speed_hz == 0 {
+        return None;
+    }
+    let bits_per_word = if bits_per_word == 0 {
+        8
+    } else {
+        bits_per_word
+    };
+    let bits = u128::from(word_count(len_bytes, bits_per_word)) * u128::from(bits_per_word);
+    let nanos = (bits * 1_000_000_000 + u128::from(speed_hz) - 1) / u128::from(speed_hz);
+    let secs = u64::try_from(nanos / 1_000_000_000).ok()?;
+    Some(Duration::new(secs, (nanos % 1_000_000_000) as u32))
+}
+
+/// The number of words in a buffer of `len_bytes`
+fn word_count(len_bytes: usize, bits_per_word: u8) -> u64 {
+    let bytes_per_word = WordSize::try_from(bits_per_word).map_or(4, WordSize::bytes) as u64;
+    len_bytes as u64 / bytes_per_word + (len_bytes as u64 % bytes_per_word != 0) as u64
+}
+
+/// A transfer that was rejected before its message was passed to the kernel
+///
+/// This is returned inside an `io::Error` of kind `InvalidInput`; it can
+/// be recovered with `io::Error::get_ref` and `downcast_ref`.  No part of
+/// a message is performed if any of its transfers is invalid.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidTransfer {
+    /// Position of the offending transfer within its message
+    pub index: usize,
+    /// What is wrong with the transfer
+    pub reason: &'static str,
+}
+
+impl fmt::Display for InvalidTransfer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid transfer {}: {}", self.index, self.reason)
+    }
+}
+
+impl Error for InvalidTransfer {}
+
+impl From<InvalidTransfer> for io::Error {
+    fn from(err: InvalidTransfer) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+impl<'a> spi_ioc_transfer<'a, 'a> {
+    /// A full-duplex transfer that sends `buf` and overwrites it with the
+    /// data received
+    ///
+    /// The kernel allows the transmit and receive buffers to be the same
+    /// memory, so this needs no second buffer.
+    ///
+    /// A buffer longer than `u32::MAX` bytes is clamped as for `read`,
+    /// so the kernel rejects the transfer; use `try_in_place` to get an
+    /// error up front.
+    pub fn in_place(buf: &'a mut [u8]) -> Self {
+        spi_ioc_transfer {
+            tx_buf: tx_addr(buf),
+            rx_buf: rx_addr(buf),
+            len: clamped_len(buf.len()),
+            ..Default::default()
+        }
+    }
+
+    /// Like `in_place`, but returns an error if the buffer length does not
+    /// fit in the kernel's `u32` length field.
+    pub fn try_in_place(buf: &'a mut [u8]) -> io::Result<Self> {
+        Ok(spi_ioc_transfer {
+            tx_buf: tx_addr(buf),
+            rx_buf: rx_addr(buf),
+            len: transfer_len(buf.len())?,
+            ..Default::default()
+        })
+    }
+}
+
+/// Check the fields of each transfer in a message
+///
+/// Transfers that don't override `bits_per_word` are checked against
+/// `default_bits_per_word`, which is zero if the device's setting is
+/// not known.
+pub(crate) fn validate(transfers: &[SpidevTransfer], default_bits_per_word: u8) -> Result<(), InvalidTransfer> {
+    for (index, transfer) in transfers.iter().enumerate() {
+        let bits_per_word = match transfer.bits_per_word {
+            0 => default_bits_per_word,
+            bits_per_word => bits_per_word,
+        };
+        let reason = if transfer.bits_per_word > 32 {
+            "bits_per_word must be at most 32"
+        } else if !word_aligned(transfer.len, bits_per_word) {
+            "length is not a whole number of words for bits_per_word"
+        } else if !valid_nbits(transfer.tx_nbits) {
+            "tx_nbits must be 1, 2, 4 or 8"
+        } else if !valid_nbits(transfer.rx_nbits) {
+            "rx_nbits must be 1, 2, 4 or 8"
+        } else if partially_overlapping(transfer) {
+            "tx_buf and rx_buf partially overlap"
+        } else {
+            continue;
+        };
+        return Err(InvalidTransfer { index, reason });
+    }
+    Ok(())
+}
+
+/// Whether `len` bytes hold a whole number of words
+///
+/// The kernel rejects transfers that end part way through a word.
+fn word_aligned(len: u32, bits_per_word: u8) -> bool {
+    match WordSize::try_from(bits_per_word) {
+        Ok(size) => len as usize % size.bytes() == 0,
+        Err(_) => true,
+    }
+}
+
+/// Whether the transmit and receive buffers share some but not all of
+/// their memory
+///
+/// Identical buffers, as from `in_place`, are fine, but the kernel
+/// doesn't support buffers that are offset from each other: controllers
+/// may overwrite transmit data that has yet to be sent.
+fn partially_overlapping(transfer: &SpidevTransfer) -> bool {
+    let (tx, rx, len) = (transfer.tx_buf, transfer.rx_buf, u64::from(transfer.len));
+    tx != 0 && rx != 0 && tx != rx && tx < rx + len && rx < tx + len
+}
+
+/// Whether `nbits` is a usable line count (zero meaning the default)
+///
+/// Dual, quad and octal transfers also need the matching
+/// `SPI_TX_*`/`SPI_RX_*` mode flag, which the controller must support.
+fn valid_nbits(nbits: u8) -> bool {
+    matches!(nbits, 0 | 1 | 2 | 4 | 8)
+}
+
+/// The address of a transmit buffer as passed to the kernel
+///
+/// Empty buffers are passed as null so that the kernel sees a
+/// bufferless transfer rather than a dangling pointer.
+fn tx_addr(buff: &[u8]) -> u64 {
+    if buff.is_empty() {
+        0
+    } else {
+        buff.as_ptr() as *const () as usize as u64
+    }
+}
+
+/// The address of a receive buffer as passed to the kernel
+///
+/// See `tx_addr`.
+fn rx_addr(buff: &mut [u8]) -> u64 {
+    if buff.is_empty() {
+        0
+    } else {
+        buff.as_mut_ptr() as *mut () as usize as u64
+    }
+}
+
+/// Convert a buffer length to the `u32` used by `spi_ioc_transfer`
+///
+/// Lengths that do not fit are rejected rather than being truncated,
+/// which would silently transfer only part of the buffer.
+fn transfer_len(len: usize) -> io::Result<u32> {
+    if len as u64 > u64::from(u32::MAX) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "transfer buffer is longer than u32::MAX bytes"));
+    }
+    Ok(len as u32)
+}
+
+/// Convert a buffer length for the kernel's `u32` length field,
+/// saturating at `u32::MAX`
+///
+/// No transfer that long fits in spidev's buffer, so the kernel rejects
+/// it with `EMSGSIZE` rather than sending a wrapped, shorter length.
+fn clamped_len(len: usize) -> u32 {
+    if len as u64 > u64::from(u32::MAX) {
+        u32::MAX
+    } else {
+        len as u32
+    }
 }
 
 mod ioctl {
     use super::spi_ioc_transfer;
 
-    const SPI_IOC_MAGIC: u8 = 'k' as u8;
+    const SPI_IOC_MAGIC: u8 = b'k';
     const SPI_IOC_NR_TRANSFER: u8 = 0;
     const SPI_IOC_NR_MODE: u8 = 1;
     const SPI_IOC_NR_LSB_FIRST: u8 = 2;
@@ -124,7 +561,7 @@ mod ioctl {
     const SPI_IOC_NR_MODE32: u8 = 5;
 
     ioctl!(read get_mode_u8 with SPI_IOC_MAGIC, SPI_IOC_NR_MODE; u8);
-    ioctl!(read get_mode_u32 with SPI_IOC_MAGIC, SPI_IOC_NR_MODE; u32);
+    ioctl!(read get_mode_u32 with SPI_IOC_MAGIC, SPI_IOC_NR_MODE32; u32);
     ioctl!(write set_mode_u8 with SPI_IOC_MAGIC, SPI_IOC_NR_MODE; u8);
     ioctl!(write set_mode_u32 with SPI_IOC_MAGIC, SPI_IOC_NR_MODE32; u32);
 
@@ -151,27 +588,68 @@ pub type SpidevTransfer<'a, 'b> = spi_ioc_transfer<'a, 'b>;
 
 pub fn get_mode(fd: RawFd) -> io::Result<u8> {
     let mut mode: u8 = 0;
-    try!(from_nix_result(unsafe { ioctl::get_mode_u8(fd, &mut mode) }));
+    from_nix_result(unsafe { ioctl::get_mode_u8(fd, &mut mode) })?;
     Ok(mode)
 }
 
+/// Read the full 32-bit mode
+///
+/// `SPI_IOC_RD_MODE32` is only present in 3.15+ kernels; on older
+/// kernels this falls back to reading the 8-bit mode.
+pub fn get_mode32(fd: RawFd) -> io::Result<u32> {
+    let mut mode: u32 = 0;
+    match from_nix_result(unsafe { ioctl::get_mode_u32(fd, &mut mode) }) {
+        Ok(_) => Ok(mode),
+        Err(ref err) if err.raw_os_error() == Some(::libc::ENOTTY) ||
+                        err.raw_os_error() == Some(::libc::EINVAL) => {
+            get_mode(fd).map(u32::from)
+        }
+        Err(err) => Err(err),
+    }
+}
+
 pub fn set_mode(fd: RawFd, mode: SpiModeFlags) -> io::Result<()> {
     // we will always use the 8-bit mode write unless bits not in
     // the 8-bit mask are used.  This is because WR_MODE32 was not
     // added until later kernels.  This provides a reasonable story
     // for forwards and backwards compatibility
-    if (mode.bits & 0xFFFFFF00) != 0 {
-        try!(from_nix_result(unsafe { ioctl::set_mode_u32(fd, &mode.bits) }));
+    if needs_mode32(mode) {
+        from_nix_result(unsafe { ioctl::set_mode_u32(fd, &mode.bits) })?;
     } else {
         let bits: u8 = mode.bits as u8;
-        try!(from_nix_result(unsafe { ioctl::set_mode_u8(fd, &bits) }));
+        from_nix_result(unsafe { ioctl::set_mode_u8(fd, &bits) })?;
     }
     Ok(())
 }
 
+/// Write the full 32-bit mode, including bits `SpiModeFlags` doesn't
+/// define
+///
+/// This uses `SPI_IOC_WR_MODE32`, so a mode read with `get_mode32` can
+/// be written back exactly.  Kernels before 3.15 only have the 8-bit
+/// write, which is used instead if the mode fits in it.
+pub fn set_mode32(fd: RawFd, mode: u32) -> io::Result<()> {
+    match from_nix_result(unsafe { ioctl::set_mode_u32(fd, &mode) }) {
+        Ok(_) => Ok(()),
+        Err(ref err) if mode & 0xFFFFFF00 == 0 &&
+                        (err.raw_os_error() == Some(::libc::ENOTTY) ||
+                         err.raw_os_error() == Some(::libc::EINVAL)) => {
+            from_nix_result(unsafe { ioctl::set_mode_u8(fd, &(mode as u8)) })?;
+            Ok(())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Whether `mode` has bits outside the 8-bit mode, such as the dual,
+/// quad and octal flags
+fn needs_mode32(mode: SpiModeFlags) -> bool {
+    (mode.bits() & 0xFFFFFF00) != 0
+}
+
 pub fn get_lsb_first(fd: RawFd) -> io::Result<u8> {
     let mut lsb_first: u8 = 0;
-    try!(from_nix_result(unsafe { ioctl::get_lsb_first(fd, &mut lsb_first) }));
+    from_nix_result(unsafe { ioctl::get_lsb_first(fd, &mut lsb_first) })?;
     Ok(lsb_first)
 }
 
@@ -181,44 +659,585 @@ pub fn set_lsb_first(fd: RawFd, lsb_first: bool) -> io::Result<()> {
     } else {
         0
     };
-    try!(from_nix_result(unsafe { ioctl::set_lsb_first(fd, &lsb_first_value) }));
+    from_nix_result(unsafe { ioctl::set_lsb_first(fd, &lsb_first_value) })?;
     Ok(())
 }
 
 pub fn get_bits_per_word(fd: RawFd) -> io::Result<u8> {
     let mut bits_per_word: u8 = 0;
-    try!(from_nix_result(unsafe { ioctl::get_bits_per_word(fd, &mut bits_per_word) }));
+    from_nix_result(unsafe { ioctl::get_bits_per_word(fd, &mut bits_per_word) })?;
     Ok(bits_per_word)
 }
 
 pub fn set_bits_per_word(fd: RawFd, bits_per_word: u8) -> io::Result<()> {
-    try!(from_nix_result(unsafe { ioctl::set_bits_per_word(fd, &bits_per_word) }));
+    from_nix_result(unsafe { ioctl::set_bits_per_word(fd, &bits_per_word) })?;
     Ok(())
 }
 
 pub fn get_max_speed_hz(fd: RawFd) -> io::Result<u32> {
     let mut max_speed_hz: u32 = 0;
-    try!(from_nix_result(unsafe { ioctl::get_max_speed_hz(fd, &mut max_speed_hz) }));
+    from_nix_result(unsafe { ioctl::get_max_speed_hz(fd, &mut max_speed_hz) })?;
     Ok(max_speed_hz)
 }
 
 pub fn set_max_speed_hz(fd: RawFd, max_speed_hz: u32) -> io::Result<()> {
-    try!(from_nix_result(unsafe { ioctl::set_max_speed_hz(fd, &max_speed_hz) }));
+    from_nix_result(unsafe { ioctl::set_max_speed_hz(fd, &max_speed_hz) })?;
     Ok(())
 }
 
-pub fn transfer(fd: RawFd, transfer: &mut SpidevTransfer) -> io::Result<()> {
+/// Perform a single transfer, returning the number of bytes transferred
+///
+/// The transfer is checked before it is performed; see `InvalidTransfer`.
+pub fn transfer(fd: RawFd, transfer: &mut SpidevTransfer) -> io::Result<u32> {
+    validate(std::slice::from_ref(transfer), 0)?;
     // The kernel will directly modify the rx_buf of the SpidevTransfer
     // rx_buf if present, so there is no need to do any additional work
-    try!(from_nix_result(unsafe { ioctl::spidev_transfer(fd, transfer) }));
-    Ok(())
+    let bytes = from_nix_result(unsafe { ioctl::spidev_transfer(fd, transfer) })?;
+    Ok(bytes as u32)
+}
+
+/// Perform a single transfer, also returning how long the ioctl took
+///
+/// Validation happens before the clock is started, so the elapsed time
+/// covers only the `SPI_IOC_MESSAGE` call.  This includes any time spent
+/// queued behind other users of the bus, which makes it useful for
+/// spotting controller stalls.
+pub fn transfer_timed(fd: RawFd, transfer: &mut SpidevTransfer) -> io::Result<(u32, Duration)> {
+    validate(std::slice::from_ref(transfer), 0)?;
+    let start = Instant::now();
+    let bytes = from_nix_result(unsafe { ioctl::spidev_transfer(fd, transfer) })?;
+    Ok((bytes as u32, start.elapsed()))
 }
 
-pub fn transfer_multiple(fd: RawFd, transfers: &mut [SpidevTransfer]) -> io::Result<()> {
-    let tot_size = transfers.len() * mem::size_of::<SpidevTransfer>();
+/// Write `tx_buf` in a single transfer, discarding anything received
+///
+/// No receive buffer is allocated; the kernel is passed a null `rx_buf`,
+/// which it accepts for write-only transfers.
+pub fn write_discard(fd: RawFd, tx_buf: &[u8]) -> io::Result<u32> {
+    transfer(fd, &mut SpidevTransfer::write(tx_buf))
+}
 
-    try!(from_nix_result(unsafe {
+/// Perform multiple transfers as one message, returning the total number
+/// of bytes transferred
+///
+/// Every transfer is checked before any of them is performed; the first
+/// invalid one is reported as an `InvalidTransfer` with its index.  This
+/// includes checking that each transfer that sets its own
+/// `bits_per_word` is a whole number of words long.
+pub fn transfer_multiple(fd: RawFd, transfers: &mut [SpidevTransfer]) -> io::Result<u32> {
+    validate(transfers, 0)?;
+    let tot_size = mem::size_of_val(transfers);
+
+    let bytes = from_nix_result(unsafe {
         ioctl::spidev_transfer_buf(fd, transfers.as_mut_ptr(), tot_size)
-    }));
-    Ok(())
+    })?;
+    Ok(bytes as u32)
+}
+
+/// Send a command and clock in a response of `resp_len` bytes
+///
+/// The command and the response are two transfers of a single message,
+/// so chip select stays asserted from the first command byte to the
+/// last response byte.  Nothing is driven on MOSI during the response,
+/// whose length is independent of the command's.
+pub fn command_response(fd: RawFd, cmd: &[u8], resp_len: usize) -> io::Result<Vec<u8>> {
+    command_response_with(cmd, resp_len, |transfers| transfer_multiple(fd, transfers).map(|_| ()))
+}
+
+/// `command_response`, performing the message with `run`
+pub(crate) fn command_response_with<F>(cmd: &[u8], resp_len: usize, run: F) -> io::Result<Vec<u8>>
+    where F: FnOnce(&mut [SpidevTransfer]) -> io::Result<()>
+{
+    let mut resp = vec![0; resp_len];
+    {
+        let mut transfers = [SpidevTransfer::write(cmd), SpidevTransfer::read(&mut resp)];
+        run(&mut transfers)?;
+    }
+    Ok(resp)
+}
+
+/// Build a transfer for each `(tx_buf, rx_len)` pair, reading into
+/// `rx_bufs`, which must hold a buffer of `rx_len` bytes for each pair
+pub(crate) fn batch_transfers<'a, 'b>(steps: &[(&'a [u8], usize)],
+                                      rx_bufs: &'b mut [Vec<u8>])
+                                      -> Result<Vec<SpidevTransfer<'a, 'b>>, InvalidTransfer> {
+    let mut transfers = Vec::with_capacity(steps.len());
+    for (index, (&(tx_buf, rx_len), rx_buf)) in steps.iter().zip(rx_bufs).enumerate() {
+        let transfer = if rx_len == 0 {
+            SpidevTransfer::try_write(tx_buf)
+        } else if tx_buf.is_empty() {
+            SpidevTransfer::try_read(rx_buf)
+        } else if tx_buf.len() == rx_len {
+            SpidevTransfer::try_read_write(tx_buf, rx_buf)
+        } else {
+            return Err(InvalidTransfer {
+                index,
+                reason: "rx_len must be zero or the length of tx_buf",
+            });
+        };
+        transfers.push(transfer.map_err(|_| {
+            InvalidTransfer {
+                index,
+                reason: "buffer is longer than u32::MAX bytes",
+            }
+        })?);
+    }
+    Ok(transfers)
+}
+
+/// Perform a `(tx_buf, rx_len)` pair per transfer as one message,
+/// returning the bytes received by each
+///
+/// A pair with an empty `tx_buf` reads `rx_len` bytes, one with an
+/// `rx_len` of zero only writes, and one with both is full-duplex, in
+/// which case `rx_len` must equal the length of `tx_buf`.  The returned
+/// buffers line up with `steps`, so write-only transfers get an empty
+/// one.  Chip select stays asserted for the whole message.  If any pair
+/// is invalid nothing is performed and an `InvalidTransfer` error
+/// identifies it.
+pub fn transfer_all(fd: RawFd, steps: &[(&[u8], usize)]) -> io::Result<Vec<Vec<u8>>> {
+    transfer_all_with(steps, |transfers| transfer_multiple(fd, transfers).map(|_| ()))
+}
+
+/// `transfer_all`, performing the message with `run`
+pub(crate) fn transfer_all_with<F>(steps: &[(&[u8], usize)], run: F) -> io::Result<Vec<Vec<u8>>>
+    where F: FnOnce(&mut [SpidevTransfer]) -> io::Result<()>
+{
+    let mut rx_bufs: Vec<Vec<u8>> = steps.iter().map(|&(_, rx_len)| vec![0; rx_len]).collect();
+    run(&mut batch_transfers(steps, &mut rx_bufs)?)?;
+    Ok(rx_bufs)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::mem;
+    use std::ptr;
+    use std::slice;
+    use std::time::Duration;
+    use libc;
+    use super::{batch_transfers, clamped_len, command_response_with, estimated_duration, needs_mode32,
+                transfer_all_with, transfer_len, validate, InvalidTransfer, Microseconds, SpidevTransfer,
+                WordDelay};
+    use words::WordSize;
+    use test::test_device;
+    use {SpiModeFlags, Spidev};
+
+    #[test]
+    fn test_transfer_layout() {
+        let transfer = SpidevTransfer::empty();
+        let base = &transfer as *const _ as usize;
+        let offset = |field: *const u8| field as usize - base;
+        // the original layout
+        assert_eq!(offset(&transfer.tx_buf as *const u64 as *const u8), 0);
+        assert_eq!(offset(&transfer.rx_buf as *const u64 as *const u8), 8);
+        assert_eq!(offset(&transfer.len as *const u32 as *const u8), 16);
+        assert_eq!(offset(&transfer.speed_hz as *const u32 as *const u8), 20);
+        assert_eq!(offset(&transfer.delay_usecs as *const u16 as *const u8), 24);
+        assert_eq!(offset(&transfer.bits_per_word), 26);
+        assert_eq!(offset(&transfer.cs_change), 27);
+        // the fields carved out of the original 32-bit pad
+        assert_eq!(offset(&transfer.tx_nbits), 28);
+        assert_eq!(offset(&transfer.rx_nbits), 29);
+        assert_eq!(offset(&transfer.word_delay_usecs), 30);
+        assert_eq!(offset(&transfer.pad), 31);
+        assert_eq!(mem::size_of::<SpidevTransfer>(), 32);
+    }
+
+    #[test]
+    fn test_transfer_len_boundary() {
+        assert_eq!(transfer_len(0).unwrap(), 0);
+        assert_eq!(transfer_len(u32::MAX as usize).unwrap(), u32::MAX);
+        assert_eq!(clamped_len(0), 0);
+        assert_eq!(clamped_len(u32::MAX as usize), u32::MAX);
+    }
+
+    #[test]
+    fn test_command_response_with() {
+        let resp = command_response_with(&[0x9F], 3, |transfers| {
+                       assert_eq!(transfers.len(), 2);
+                       assert_eq!(transfers[0].tx_slice(), [0x9F]);
+                       assert_eq!(transfers[1].tx_slice(), []);
+                       transfers[1].rx_slice_mut().copy_from_slice(&[0xEF, 0x40, 0x18]);
+                       Ok(())
+                   })
+                       .unwrap();
+        assert_eq!(resp, [0xEF, 0x40, 0x18]);
+    }
+
+    #[test]
+    fn test_transfer_all_with() {
+        let rx = transfer_all_with(&[(&[0x01], 0), (&[], 2), (&[0x02, 0x03], 2)], |transfers| {
+                     assert_eq!(transfers.len(), 3);
+                     transfers[1].rx_slice_mut().copy_from_slice(&[0xA0, 0xA1]);
+                     transfers[2].rx_slice_mut().copy_from_slice(&[0xB0, 0xB1]);
+                     Ok(())
+                 })
+                     .unwrap();
+        assert_eq!(rx, [vec![], vec![0xA0, 0xA1], vec![0xB0, 0xB1]]);
+
+        // an invalid pair is reported without running the message
+        let err = transfer_all_with(&[(&[0x01, 0x02], 1)], |_| panic!("message was run")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn test_transfer_len_overflow() {
+        let err = transfer_len(u32::MAX as usize + 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(clamped_len(u32::MAX as usize + 1), u32::MAX);
+        assert_eq!(clamped_len(usize::MAX), u32::MAX);
+    }
+
+    /// Run `f` on a zeroed buffer one byte longer than `u32::MAX`
+    ///
+    /// The mapping is not backed until it is touched, so this needs
+    /// address space but not memory.
+    #[cfg(target_pointer_width = "64")]
+    fn with_oversized_buffer<F: FnOnce(&mut [u8])>(f: F) {
+        let len = u32::MAX as usize + 1;
+        let addr = unsafe {
+            libc::mmap(ptr::null_mut(),
+                       len,
+                       libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                       -1,
+                       0)
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+        f(unsafe { slice::from_raw_parts_mut(addr as *mut u8, len) });
+        unsafe { libc::munmap(addr, len) };
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn test_oversized_buffer_clamped() {
+        with_oversized_buffer(|buf| {
+            assert_eq!(SpidevTransfer::read(buf).len, u32::MAX);
+            assert_eq!(SpidevTransfer::write(buf).len, u32::MAX);
+            let err = SpidevTransfer::try_write(buf).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        });
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn test_oversized_in_place_clamped() {
+        with_oversized_buffer(|buf| {
+            assert_eq!(SpidevTransfer::in_place(buf).len, u32::MAX);
+            let err = SpidevTransfer::try_in_place(buf).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        });
+    }
+
+    #[test]
+    fn test_clear_rx() {
+        let mut rx_buf = [0xAA; 4];
+        {
+            let mut transfer = SpidevTransfer::read(&mut rx_buf);
+            transfer.clear_rx();
+        }
+        assert_eq!(rx_buf, [0; 4]);
+    }
+
+    #[test]
+    fn test_clear_rx_without_rx_buf() {
+        let tx_buf = [0xAA; 4];
+        let mut transfer = SpidevTransfer::write(&tx_buf);
+        transfer.clear_rx();
+        assert_eq!(tx_buf, [0xAA; 4]);
+    }
+
+    #[test]
+    fn test_estimated_duration() {
+        assert_eq!(estimated_duration(1000, 8, 1_000_000), Some(Duration::from_millis(8)));
+        assert_eq!(estimated_duration(1000, 0, 1_000_000), Some(Duration::from_millis(8)));
+        assert_eq!(estimated_duration(3, 8, 8_000_000), Some(Duration::from_micros(3)));
+        // five 12-bit words, each stored in two bytes
+        assert_eq!(estimated_duration(10, 12, 1_000_000), Some(Duration::from_micros(60)));
+        assert_eq!(estimated_duration(4, 32, 32_000_000), Some(Duration::from_micros(1)));
+        assert_eq!(estimated_duration(0, 8, 1_000_000), Some(Duration::from_secs(0)));
+        assert_eq!(estimated_duration(1_000_000, 8, 3), Some(Duration::new(2_666_666, 666_666_667)));
+    }
+
+    #[test]
+    fn test_estimated_duration_unknown() {
+        assert_eq!(estimated_duration(16, 8, 0), None);
+        let tx_buf = [0; 4];
+        let mut transfer = SpidevTransfer::write(&tx_buf);
+        assert_eq!(transfer.estimated_duration(8, 0), None);
+        transfer.speed_hz = 1_000_000;
+        assert_eq!(transfer.estimated_duration(8, 0), Some(Duration::from_micros(32)));
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn test_estimated_duration_overflow() {
+        // more seconds than a Duration holds
+        assert_eq!(estimated_duration(usize::MAX, 32, 1), None);
+    }
+
+    #[test]
+    fn test_transfer_estimated_duration() {
+        let tx_buf = [0; 1000];
+        let mut transfer = SpidevTransfer::write(&tx_buf);
+        transfer.delay_usecs = 10;
+        assert_eq!(transfer.estimated_duration(8, 1_000_000),
+                   Some(Duration::from_micros(8_010)));
+        transfer.speed_hz = 2_000_000;
+        assert_eq!(transfer.estimated_duration(8, 1_000_000),
+                   Some(Duration::from_micros(4_010)));
+    }
+
+    #[test]
+    fn test_zero_length_transfers() {
+        let mut rx_buf = [];
+        let read = SpidevTransfer::read(&mut rx_buf);
+        assert_eq!((read.tx_buf, read.rx_buf, read.len), (0, 0, 0));
+        let write = SpidevTransfer::write(&[]);
+        assert_eq!((write.tx_buf, write.rx_buf, write.len), (0, 0, 0));
+        let empty = SpidevTransfer::empty();
+        assert_eq!((empty.tx_buf, empty.rx_buf, empty.len), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_zero_length_transfer_error() {
+        // not a spidev device, so the ioctl fails but nothing panics
+        let spidev = Spidev::open("/dev/null").unwrap();
+        assert!(spidev.transfer(&mut SpidevTransfer::empty()).is_err());
+    }
+
+    #[test]
+    #[ignore]
+    fn test_zero_length_transfer_device() {
+        let spidev = test_device();
+        let mut transfer = SpidevTransfer::empty();
+        transfer.delay_usecs = 10;
+        spidev.transfer(&mut transfer).unwrap();
+    }
+
+    #[test]
+    fn test_delay_builders() {
+        let tx_buf = [0; 4];
+        let transfer = SpidevTransfer::write(&tx_buf)
+                           .delay_usecs(Microseconds(300))
+                           .word_delay_usecs(WordDelay(5));
+        assert_eq!(transfer.delay_usecs, 300);
+        assert_eq!(transfer.word_delay_usecs, 5);
+
+        let (delay, word_delay): (u16, u8) = (10, 2);
+        let transfer = SpidevTransfer::write(&tx_buf)
+                           .delay_usecs(delay)
+                           .word_delay_usecs(word_delay);
+        assert_eq!(transfer.delay_usecs, 10);
+        assert_eq!(transfer.word_delay_usecs, 2);
+    }
+
+    #[test]
+    fn test_transfer_estimated_duration_word_delay() {
+        // four words at 1us each, with 2us between each pair of words
+        let tx_buf = [0; 4];
+        let transfer = SpidevTransfer::write(&tx_buf).word_delay_usecs(2u8);
+        assert_eq!(transfer.estimated_duration(8, 8_000_000), Some(Duration::from_micros(10)));
+    }
+
+    #[test]
+    fn test_write_has_no_rx_buf() {
+        let tx_buf = [0x01, 0x02, 0x03];
+        let transfer = SpidevTransfer::write(&tx_buf);
+        assert_eq!(transfer.rx_buf, 0);
+        assert_eq!(transfer.tx_buf, tx_buf.as_ptr() as usize as u64);
+        assert_eq!(transfer.len, 3);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_write_discard_device() {
+        let spidev = test_device();
+        assert_eq!(spidev.write_discard(&[0x55; 64]).unwrap(), 64);
+    }
+
+    #[test]
+    fn test_cs_timing_builders() {
+        let tx_buf = [0; 4];
+        let transfer = SpidevTransfer::write(&tx_buf).cs_change(true).delay_usecs(50u16);
+        assert_eq!(transfer.cs_change, 1);
+        assert_eq!(transfer.delay_usecs, 50);
+        // no extra delay unless one is requested
+        let transfer = SpidevTransfer::write(&tx_buf).cs_change(false);
+        assert_eq!(transfer.cs_change, 0);
+        assert_eq!(transfer.delay_usecs, 0);
+        assert_eq!(transfer.word_delay_usecs, 0);
+    }
+
+    #[test]
+    fn test_invalid_transfer_index() {
+        let spidev = Spidev::open("/dev/null").unwrap();
+        let tx_buf = [0; 4];
+        let mut transfers = [SpidevTransfer::write(&tx_buf),
+                             SpidevTransfer::write(&tx_buf),
+                             SpidevTransfer::write(&tx_buf),
+                             SpidevTransfer::write(&tx_buf)];
+        transfers[2].bits_per_word = 33;
+        // rejected before the ioctl, which would fail with ENOTTY
+        let err = spidev.transfer_multiple(&mut transfers).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let invalid = err.get_ref().unwrap().downcast_ref::<InvalidTransfer>().unwrap();
+        assert_eq!(invalid.index, 2);
+    }
+
+    #[test]
+    fn test_octal() {
+        let mode = SpiModeFlags::SPI_TX_OCTAL | SpiModeFlags::SPI_RX_OCTAL | SpiModeFlags::SPI_MODE_3;
+        assert_eq!(mode.bits(), 0x6003);
+        assert!(needs_mode32(mode));
+        assert!(!needs_mode32(SpiModeFlags::SPI_MODE_3 | SpiModeFlags::SPI_CS_HIGH));
+        assert_eq!(SpiModeFlags::from_mode_bits(0x6003).unwrap(), mode);
+
+        let tx_buf = [0; 4];
+        let mut transfer = SpidevTransfer::write(&tx_buf);
+        transfer.tx_nbits = 8;
+        transfer.rx_nbits = 8;
+        let transfers = [transfer];
+        assert!(validate(&transfers, 0).is_ok());
+        let raw = unsafe { slice::from_raw_parts(transfers.as_ptr() as *const u8, 32) };
+        assert_eq!(&raw[28..30], [8, 8]);
+    }
+
+    #[test]
+    fn test_invalid_nbits() {
+        let spidev = Spidev::open("/dev/null").unwrap();
+        let tx_buf = [0; 4];
+        let mut transfer = SpidevTransfer::write(&tx_buf);
+        transfer.tx_nbits = 3;
+        let err = spidev.transfer(&mut transfer).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_transfer_timed_device() {
+        let spidev = test_device();
+        let tx_buf = [0xa5; 32];
+        let mut rx_buf = [0; 32];
+        let mut transfer = SpidevTransfer::read_write(&tx_buf, &mut rx_buf);
+        let (bytes, elapsed) = spidev.transfer_timed(&mut transfer).unwrap();
+        assert_eq!(bytes, 32);
+        assert!(elapsed > Duration::from_secs(0));
+        // requires MOSI to be looped back to MISO
+        assert_eq!(rx_buf, tx_buf);
+    }
+
+    #[test]
+    fn test_rx_words() {
+        let tx_buf = [0; 6];
+        let mut rx_buf = [0; 6];
+        rx_buf[..2].copy_from_slice(&0x1234u16.to_ne_bytes());
+        rx_buf[2..4].copy_from_slice(&0x01ffu16.to_ne_bytes());
+        let transfer = SpidevTransfer::read_write(&tx_buf, &mut rx_buf);
+        assert_eq!(transfer.rx_words_u16().collect::<Vec<_>>(), [0x1234, 0x01ff, 0]);
+        assert_eq!(transfer.rx_words_u32().len(), 1);
+        assert_eq!(SpidevTransfer::write(&tx_buf).rx_words_u16().count(), 0);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_rx_words_device() {
+        let spidev = test_device();
+        let words = [0x0155u16, 0x00aa, 0x01ff];
+        let mut tx_buf = Vec::new();
+        for word in &words {
+            tx_buf.extend_from_slice(&word.to_ne_bytes());
+        }
+        let mut rx_buf = vec![0; tx_buf.len()];
+        let mut transfer = SpidevTransfer::read_write(&tx_buf, &mut rx_buf);
+        transfer.bits_per_word = 9;
+        spidev.transfer(&mut transfer).unwrap();
+        // requires MOSI to be looped back to MISO
+        assert_eq!(transfer.rx_words_u16().collect::<Vec<_>>(), words);
+    }
+
+    #[test]
+    fn test_misaligned_transfer_index() {
+        let spidev = Spidev::open("/dev/null").unwrap();
+        let aligned = [0; 4];
+        let misaligned = [0; 3];
+        let mut transfers = [SpidevTransfer::write(&aligned).word_size(WordSize::Bits16),
+                             SpidevTransfer::write(&misaligned).word_size(WordSize::Bits16)];
+        let err = spidev.transfer_multiple(&mut transfers).unwrap_err();
+        let invalid = err.get_ref().unwrap().downcast_ref::<InvalidTransfer>().unwrap();
+        assert_eq!(invalid.index, 1);
+        // a 24-bit word occupies four bytes
+        let mut transfer = SpidevTransfer::write(&misaligned).word_size(WordSize::Bits24);
+        assert_eq!(spidev.transfer(&mut transfer).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_validate_default_bits_per_word() {
+        let tx_buf = [0; 3];
+        let transfers = [SpidevTransfer::write(&tx_buf),
+                         SpidevTransfer::write(&tx_buf).word_size(WordSize::Bits8)];
+        assert!(validate(&transfers, 0).is_ok());
+        // the first transfer uses the default, the second overrides it
+        assert_eq!(validate(&transfers, 16).unwrap_err().index, 0);
+        assert!(validate(&transfers[1..], 16).is_ok());
+    }
+
+    #[test]
+    fn test_batch_transfers() {
+        let cmd = [0x01, 0x02];
+        let mut rx_bufs = vec![vec![], vec![0; 4], vec![0; 2]];
+        let transfers = batch_transfers(&[(&cmd, 0), (&[], 4), (&cmd, 2)], &mut rx_bufs).unwrap();
+        assert_eq!((transfers[0].len, transfers[0].rx_buf), (2, 0));
+        assert_eq!((transfers[1].len, transfers[1].tx_buf), (4, 0));
+        assert_eq!(transfers[2].len, 2);
+        assert!(transfers[2].tx_buf != 0 && transfers[2].rx_buf != 0);
+
+        let mut rx_bufs = vec![vec![], vec![0; 3]];
+        let err = batch_transfers(&[(&cmd, 0), (&cmd, 3)], &mut rx_bufs).unwrap_err();
+        assert_eq!(err.index, 1);
+    }
+
+    #[test]
+    fn test_in_place() {
+        let mut buf = [0x5a; 8];
+        let transfer = SpidevTransfer::in_place(&mut buf);
+        assert_eq!(transfer.tx_buf, transfer.rx_buf);
+        assert_eq!(transfer.len, 8);
+        assert!(validate(&[transfer], 0).is_ok());
+    }
+
+    #[test]
+    fn test_partial_overlap() {
+        let mut buf = [0; 8];
+        let mut transfer = SpidevTransfer::in_place(&mut buf[..7]);
+        // only reachable through the private fields, but guards against
+        // a constructor ever producing it
+        transfer.rx_buf += 1;
+        let err = validate(&[SpidevTransfer::empty(), transfer], 0).unwrap_err();
+        assert_eq!(err.index, 1);
+        assert_eq!(err.reason, "tx_buf and rx_buf partially overlap");
+    }
+
+    #[test]
+    #[ignore]
+    fn test_in_place_device() {
+        let spidev = test_device();
+        let mut buf = [0x01, 0x02, 0x03, 0x04];
+        spidev.transfer(&mut SpidevTransfer::in_place(&mut buf)).unwrap();
+        // requires MOSI to be looped back to MISO
+        assert_eq!(buf, [0x01, 0x02, 0x03, 0x04]);
+    }
+
+    #[test]
+    fn test_nop() {
+        let transfer = SpidevTransfer::nop(16);
+        // a null tx_buf is shifted out as zeroes and a null rx_buf is
+        // discarded
+        assert_eq!(transfer.tx_buf, 0);
+        assert_eq!(transfer.rx_buf, 0);
+        assert_eq!(transfer.len, 16);
+        assert_eq!(transfer.estimated_duration(8, 1_000_000), Some(Duration::from_micros(128)));
+    }
 }
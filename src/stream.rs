@@ -0,0 +1,275 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Continuous reads on a background thread
+//!
+//! `StreamingReader` keeps the bus busy for data acquisition: while the
+//! application processes one buffer, a background thread fills the next.
+
+use std::io;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use spidevioctl::SpidevTransfer;
+use Spidev;
+
+/// The number of buffers cycled between the application and the
+/// background thread
+const BUFFERS: usize = 2;
+
+struct Filled {
+    buf: Vec<u8>,
+    sequence: u64,
+    stalled: bool,
+}
+
+/// Reads a device continuously into a pair of buffers
+///
+/// A background thread repeatedly fills a buffer with a read transfer
+/// and hands it to the application through `next_buffer`; buffers are
+/// handed back for reuse when the returned `StreamBuffer` is dropped.
+///
+/// # Backpressure
+///
+/// If the application falls behind, the background thread has no free
+/// buffer to read into and waits rather than overwriting data that
+/// hasn't been processed.  The bus is idle while it waits, so the device
+/// may drop or overrun samples during that time; the first buffer read
+/// after such a wait reports `stalled`, so the application can tell
+/// where the gap in the data is.
+pub struct StreamingReader {
+    filled: Receiver<io::Result<Filled>>,
+    empty: Option<Sender<Vec<u8>>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl StreamingReader {
+    /// Start reading `spidev` continuously in buffers of `buf_len` bytes
+    ///
+    /// The device is moved to the background thread, and is closed when
+    /// the reader is dropped.
+    pub fn new(spidev: Spidev, buf_len: usize) -> io::Result<StreamingReader> {
+        StreamingReader::with_fill(buf_len, move |buf| {
+            spidev.transfer(&mut SpidevTransfer::try_read(buf)?)
+        })
+    }
+
+    /// Start a stream that fills each buffer by calling `fill`
+    ///
+    /// This allows streaming with something other than a plain read
+    /// transfer, e.g. a `Message` that issues a command first.  If `fill`
+    /// fails the error is returned by `next_buffer` and the stream stops.
+    pub fn with_fill<F>(buf_len: usize, mut fill: F) -> io::Result<StreamingReader>
+        where F: FnMut(&mut [u8]) -> io::Result<()> + Send + 'static
+    {
+        let (filled_tx, filled) = mpsc::sync_channel(BUFFERS);
+        let (empty, empty_rx) = mpsc::channel();
+        for _ in 0..BUFFERS {
+            // the receiver is still alive, so this can't fail
+            let _ = empty.send(vec![0; buf_len]);
+        }
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::Builder::new().name("spidev-stream".to_string()).spawn(move || {
+            let mut sequence = 0;
+            // every buffer starts out free, so the first read can't stall
+            let mut next = empty_rx.recv().ok().map(|buf| (buf, false));
+            while let Some((mut buf, stalled)) = next {
+                // buffers handed back after the reader was dropped are
+                // not read into
+                if stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Err(err) = fill(&mut buf) {
+                    let _ = filled_tx.send(Err(err));
+                    break;
+                }
+                // whether the next read stalls is decided as soon as this
+                // one finishes, which is when the bus would go idle
+                next = match empty_rx.try_recv() {
+                    Ok(buf) => Some((buf, false)),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => break,
+                };
+                let filled = Filled {
+                    buf,
+                    sequence,
+                    stalled,
+                };
+                // there are only as many buffers as the channel holds, so
+                // this never blocks
+                if filled_tx.send(Ok(filled)).is_err() {
+                    break;
+                }
+                if next.is_none() {
+                    next = empty_rx.recv().ok().map(|buf| (buf, true));
+                }
+                sequence += 1;
+            }
+        })?;
+        Ok(StreamingReader {
+            filled,
+            empty: Some(empty),
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Wait for the next filled buffer
+    ///
+    /// Buffers are returned in the order they were read.  An error with
+    /// kind `BrokenPipe` is returned once the stream has stopped after a
+    /// failed read.
+    pub fn next_buffer(&mut self) -> io::Result<StreamBuffer<'_>> {
+        match self.filled.recv() {
+            Ok(Ok(filled)) => {
+                Ok(StreamBuffer {
+                    buf: Some(filled.buf),
+                    sequence: filled.sequence,
+                    stalled: filled.stalled,
+                    reader: self,
+                })
+            }
+            Ok(Err(err)) => Err(err),
+            Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "stream has stopped")),
+        }
+    }
+}
+
+impl Drop for StreamingReader {
+    fn drop(&mut self) {
+        // the background thread stops after the read in progress, without
+        // reading into any buffer already handed back.  Dropping the
+        // sender of free buffers wakes it if it is waiting for one
+        self.stop.store(true, Ordering::SeqCst);
+        self.empty = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A buffer filled by a `StreamingReader`
+///
+/// The buffer is handed back to the reader for reuse when this is
+/// dropped.
+pub struct StreamBuffer<'a> {
+    buf: Option<Vec<u8>>,
+    sequence: u64,
+    stalled: bool,
+    reader: &'a StreamingReader,
+}
+
+impl<'a> StreamBuffer<'a> {
+    /// The position of this buffer in the stream, counting from zero
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Whether the read into this buffer was delayed because the
+    /// application had not returned a buffer in time
+    ///
+    /// Data clocked out by the device just before this buffer may have
+    /// been lost.
+    pub fn stalled(&self) -> bool {
+        self.stalled
+    }
+}
+
+impl<'a> Deref for StreamBuffer<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.buf.as_ref().map_or(&[], |buf| buf)
+    }
+}
+
+impl<'a> Drop for StreamBuffer<'a> {
+    fn drop(&mut self) {
+        if let (Some(buf), Some(empty)) = (self.buf.take(), self.reader.empty.as_ref()) {
+            // the background thread may already have stopped
+            let _ = empty.send(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::mem;
+    use super::StreamingReader;
+    use test::test_device;
+
+    /// A stream whose buffers are filled with their sequence number
+    fn counting_stream(buf_len: usize) -> StreamingReader {
+        let mut count = 0u8;
+        StreamingReader::with_fill(buf_len, move |buf| {
+                for byte in buf.iter_mut() {
+                    *byte = count;
+                }
+                count += 1;
+                Ok(())
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_stream_order() {
+        let mut stream = counting_stream(16);
+        for i in 0..10 {
+            let buf = stream.next_buffer().unwrap();
+            assert_eq!(buf.sequence(), i);
+            assert_eq!(buf.len(), 16);
+            assert!(buf.iter().all(|&byte| byte == i as u8));
+        }
+    }
+
+    #[test]
+    fn test_stream_stall() {
+        let mut stream = counting_stream(4);
+        let buf = stream.next_buffer().unwrap();
+        assert!(!buf.stalled());
+        // never hand the first buffer back, so the background thread has
+        // none free once it has filled the second.  That is decided before
+        // the second is sent, so returning it can't race the check
+        mem::forget(buf);
+        assert!(!stream.next_buffer().unwrap().stalled());
+        let buf = stream.next_buffer().unwrap();
+        assert_eq!(buf.sequence(), 2);
+        assert!(buf.stalled());
+    }
+
+    #[test]
+    fn test_stream_error() {
+        let mut calls = 0;
+        let mut stream = StreamingReader::with_fill(4, move |_| {
+                             calls += 1;
+                             if calls > 1 {
+                                 Err(io::Error::from_raw_os_error(::libc::EIO))
+                             } else {
+                                 Ok(())
+                             }
+                         })
+                             .unwrap();
+        assert!(stream.next_buffer().is_ok());
+        assert_eq!(stream.next_buffer().err().unwrap().raw_os_error(), Some(::libc::EIO));
+        assert_eq!(stream.next_buffer().err().unwrap().kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    #[ignore]
+    fn test_stream_device() {
+        let mut stream = StreamingReader::new(test_device(), 256).unwrap();
+        for i in 0..8 {
+            assert_eq!(stream.next_buffer().unwrap().sequence(), i);
+        }
+    }
+}
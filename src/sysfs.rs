@@ -0,0 +1,479 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Information about spidev devices exposed through sysfs
+//!
+//! Each spidev node has an entry under `/sys/class/spidev` whose
+//! `device` link points at the SPI device, which is itself a child of
+//! the SPI controller it is attached to.  Controllers operating as a
+//! master are registered in the `spi_master` class and those operating
+//! as a slave in the `spi_slave` class.
+
+use std::fs;
+use std::io;
+use std::os::unix::prelude::*;
+use std::path::{Path, PathBuf};
+
+const SPIDEV_CLASS: &str = "/sys/class/spidev";
+const SPI_BUS_DEVICES: &str = "/sys/bus/spi/devices";
+const SPIDEV_BUFSIZ: &str = "/sys/module/spidev/parameters/bufsiz";
+
+/// The default of spidev's `bufsiz` module parameter
+pub const DEFAULT_BUFSIZ: usize = 4096;
+
+/// Details of the SPI controller and device backing a spidev node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControllerInfo {
+    /// Name of the controller the device is attached to (e.g. `spi0`)
+    pub controller: String,
+    /// Name of the driver bound to the controller, if known
+    pub driver: Option<String>,
+    /// Modalias of the SPI device (e.g. `spi:spidev`), if known
+    pub modalias: Option<String>,
+    /// Whether the controller operates in slave mode
+    pub slave: bool,
+}
+
+/// Transfer counters kept by the kernel for a SPI device
+///
+/// These are read from the `statistics` directory of the SPI device in
+/// sysfs and count activity since the device was registered.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Statistics {
+    /// Messages handled
+    pub messages: u64,
+    /// Transfers handled
+    pub transfers: u64,
+    /// Messages that failed
+    pub errors: u64,
+    /// Messages that timed out
+    pub timedout: u64,
+    /// Bytes transferred
+    pub bytes: u64,
+    /// Bytes transmitted
+    pub bytes_tx: u64,
+    /// Bytes received
+    pub bytes_rx: u64,
+}
+
+/// The `/sys/class/spidev` entry for the device node at `dev_path`
+///
+/// The entry is named after the device node, so `/dev/spidev0.0` maps
+/// to `/sys/class/spidev/spidev0.0`.  Symlinks to the node should be
+/// resolved by the caller first.
+pub fn sysfs_path<P: AsRef<Path>>(dev_path: P) -> io::Result<PathBuf> {
+    match dev_path.as_ref().file_name() {
+        Some(name) => Ok(Path::new(SPIDEV_CLASS).join(name)),
+        None => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                   "device path does not name a device node")),
+    }
+}
+
+/// The path of the device node that `fd` was opened from
+fn device_path(fd: RawFd) -> io::Result<PathBuf> {
+    fs::read_link(format!("/proc/self/fd/{}", fd))
+}
+
+/// The `/sys/class/spidev` entry for the device open on `fd`
+fn sysfs_path_for_fd(fd: RawFd) -> io::Result<PathBuf> {
+    sysfs_path(device_path(fd)?)
+}
+
+/// Read a sysfs attribute, without its trailing newline
+fn read_attr(path: &Path) -> io::Result<String> {
+    let value = fs::read_to_string(path)?;
+    Ok(value.trim_end().to_string())
+}
+
+/// Read a sysfs attribute holding an unsigned integer
+fn read_u64_attr(path: &Path) -> io::Result<u64> {
+    read_attr(path)?.parse().map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidData,
+                       format!("{} does not hold an integer", path.display()))
+    })
+}
+
+/// The final component of the target of the symlink at `path`
+fn link_name(path: &Path) -> io::Result<String> {
+    let target = fs::read_link(path)?;
+    match target.file_name() {
+        Some(name) => Ok(name.to_string_lossy().into_owned()),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, "symlink has no target name")),
+    }
+}
+
+/// Read the modalias of the SPI device behind the spidev entry at
+/// `class_dir`
+///
+/// The attribute is normally reached through the entry's `device`
+/// link.  If that is missing, the SPI device is looked up by name in
+/// `spi_devices` (the `/sys/bus/spi/devices` equivalent), as the bus
+/// listing has been stable across kernel versions: `spidevX.Y` is SPI
+/// device `spiX.Y`.
+fn modalias_at(class_dir: &Path, spi_devices: &Path) -> io::Result<String> {
+    let value = match read_attr(&class_dir.join("device").join("modalias")) {
+        Ok(value) => value,
+        Err(err) => {
+            let name = class_dir.file_name()
+                                .and_then(|name| name.to_str())
+                                .filter(|name| name.starts_with("spidev"))
+                                .map(|name| &name["spidev".len()..]);
+            match name {
+                Some(name) => read_attr(&spi_devices.join(format!("spi{}", name)).join("modalias"))?,
+                None => return Err(err),
+            }
+        }
+    };
+    if value.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "modalias is empty"));
+    }
+    Ok(value)
+}
+
+/// Split a modalias into its bus prefix and device identifier
+///
+/// Devices instantiated by name have a modalias such as `spi:mydevice`,
+/// while those matched through devicetree have one such as
+/// `of:NmydeviceT(null)Cvendor,mydevice`.  `None` is returned if there is
+/// no prefix.
+pub fn split_modalias(modalias: &str) -> Option<(&str, &str)> {
+    let colon = modalias.find(':')?;
+    if colon == 0 {
+        return None;
+    }
+    Some((&modalias[..colon], &modalias[colon + 1..]))
+}
+
+/// Read the modalias of the spidev device open on `fd`
+///
+/// This is the string the kernel uses to bind a driver to the SPI device
+/// (e.g. `spi:spidev`); see `split_modalias`.  An error with kind
+/// `NotFound` is returned if sysfs has no entry for the device.
+pub fn modalias(fd: RawFd) -> io::Result<String> {
+    modalias_at(&sysfs_path_for_fd(fd)?, Path::new(SPI_BUS_DEVICES))
+}
+
+/// The sysfs directory of the controller for the spidev entry at
+/// `class_dir`, e.g. `/sys/devices/.../spi_master/spi0`
+fn controller_dir_at(class_dir: &Path) -> io::Result<PathBuf> {
+    let device = class_dir.join("device");
+    if !device.exists() {
+        return Err(io::Error::new(io::ErrorKind::NotFound,
+                                  "no sysfs entry for the spidev device"));
+    }
+    match fs::canonicalize(&device)?.parent() {
+        Some(dir) => Ok(dir.to_path_buf()),
+        None => {
+            Err(io::Error::new(io::ErrorKind::NotFound,
+                               "spidev device has no parent controller"))
+        }
+    }
+}
+
+/// Find the controller and driver for the spidev entry at `class_dir`
+fn controller_info_at(class_dir: &Path) -> io::Result<ControllerInfo> {
+    let device = class_dir.join("device");
+    let controller_dir = controller_dir_at(class_dir)?;
+    let controller = match controller_dir.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                      "spidev device has no parent controller"))
+        }
+    };
+    let slave = controller_dir.parent().and_then(Path::file_name) == Some("spi_slave".as_ref());
+    Ok(ControllerInfo {
+        controller,
+        driver: link_name(&controller_dir.join("device").join("driver")).ok(),
+        modalias: read_attr(&device.join("modalias")).ok(),
+        slave,
+    })
+}
+
+/// Look up the SPI controller backing the spidev device open on `fd`
+///
+/// An error with kind `NotFound` is returned if sysfs is not mounted or
+/// does not have an entry for the device.  Attributes that are missing
+/// for an existing device are reported as `None`.
+pub fn controller_info(fd: RawFd) -> io::Result<ControllerInfo> {
+    controller_info_at(&sysfs_path_for_fd(fd)?)
+}
+
+/// Read the base clock of the controller for the spidev entry at
+/// `class_dir` from its devicetree `clock-frequency` property
+fn base_clock_hz_at(class_dir: &Path) -> io::Result<u64> {
+    let property = controller_dir_at(class_dir)?.join("device/of_node/clock-frequency");
+    let value = match fs::read(&property) {
+        Ok(value) => value,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                      "the controller does not expose its base clock: its \
+                                       devicetree node has no clock-frequency property"));
+        }
+        Err(err) => return Err(err),
+    };
+    // devicetree cells are big-endian, and the property is one cell or,
+    // for very fast clocks, two
+    match value.len() {
+        4 => Ok(u64::from(u32::from_be_bytes([value[0], value[1], value[2], value[3]]))),
+        8 => {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&value);
+            Ok(u64::from_be_bytes(bytes))
+        }
+        _ => {
+            Err(io::Error::new(io::ErrorKind::InvalidData,
+                               format!("{} is not a 32 or 64-bit value", property.display())))
+        }
+    }
+}
+
+/// Read the base clock of the SPI controller for the device open on `fd`
+///
+/// This is the `clock-frequency` devicetree property of the controller,
+/// which only some controllers have: most take their clock from the
+/// common clock framework, whose rates are not exposed in sysfs.  An
+/// error with kind `NotFound` is returned if it is not available.
+pub fn base_clock_hz(fd: RawFd) -> io::Result<u64> {
+    base_clock_hz_at(&sysfs_path_for_fd(fd)?)
+}
+
+/// Read spidev's `bufsiz` module parameter from `path`
+fn bufsiz_at(path: &Path) -> usize {
+    match read_u64_attr(path) {
+        Ok(bufsiz) if bufsiz > 0 => bufsiz as usize,
+        _ => DEFAULT_BUFSIZ,
+    }
+}
+
+/// The largest message spidev accepts, in bytes
+///
+/// This is spidev's `bufsiz` module parameter, which bounds the total
+/// length of the buffers of one message.  `DEFAULT_BUFSIZ` is returned
+/// if it can't be read, e.g. when spidev is built into the kernel
+/// without exposing its parameters.
+pub fn bufsiz() -> usize {
+    bufsiz_at(Path::new(SPIDEV_BUFSIZ))
+}
+
+/// Read the counters in the sysfs `statistics` directory at `dir`
+fn statistics_at(dir: &Path) -> io::Result<Option<Statistics>> {
+    if !dir.is_dir() {
+        return Ok(None);
+    }
+    Ok(Some(Statistics {
+        messages: read_u64_attr(&dir.join("messages"))?,
+        transfers: read_u64_attr(&dir.join("transfers"))?,
+        errors: read_u64_attr(&dir.join("errors"))?,
+        timedout: read_u64_attr(&dir.join("timedout"))?,
+        bytes: read_u64_attr(&dir.join("bytes"))?,
+        bytes_tx: read_u64_attr(&dir.join("bytes_tx"))?,
+        bytes_rx: read_u64_attr(&dir.join("bytes_rx"))?,
+    }))
+}
+
+/// Read the kernel's transfer counters for the spidev device open on `fd`
+///
+/// `None` is returned on kernels that do not keep SPI statistics (before
+/// 4.1); an error with kind `NotFound` if sysfs has no entry for the
+/// device at all.
+pub fn statistics(fd: RawFd) -> io::Result<Option<Statistics>> {
+    let device = sysfs_path_for_fd(fd)?.join("device");
+    if !device.exists() {
+        return Err(io::Error::new(io::ErrorKind::NotFound,
+                                  "no sysfs entry for the spidev device"));
+    }
+    statistics_at(&device.join("statistics"))
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::os::unix::fs::symlink;
+    use std::path::{Path, PathBuf};
+    use std::process;
+    use super::{base_clock_hz_at, bufsiz_at, controller_info_at, modalias_at, split_modalias, statistics_at, sysfs_path,
+                ControllerInfo, Statistics, DEFAULT_BUFSIZ};
+    use test::test_device;
+
+    /// A fresh, empty directory for building a fake sysfs tree
+    pub fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("spidev-test-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Build a fake sysfs tree for `spidev0.0` on controller `spi0`,
+    /// returning the `/sys/class/spidev/spidev0.0` equivalent
+    pub fn fake_spidev(root: &Path) -> PathBuf {
+        fake_spidev_in(root, "spi_master")
+    }
+
+    /// As `fake_spidev`, with the controller registered in `class`
+    pub fn fake_spidev_in(root: &Path, class: &str) -> PathBuf {
+        let platform = root.join("devices/platform/soc/fe204000.spi");
+        let controller = platform.join(class).join("spi0");
+        let device = controller.join("spi0.0");
+        fs::create_dir_all(&device).unwrap();
+        fs::create_dir_all(root.join("bus/platform/drivers/spi-bcm2835")).unwrap();
+        symlink(root.join("bus/platform/drivers/spi-bcm2835"), platform.join("driver")).unwrap();
+        symlink(&platform, controller.join("device")).unwrap();
+        fs::write(device.join("modalias"), "spi:spidev\n").unwrap();
+
+        let class_dir = root.join("class/spidev/spidev0.0");
+        fs::create_dir_all(&class_dir).unwrap();
+        symlink(&device, class_dir.join("device")).unwrap();
+        class_dir
+    }
+
+    #[test]
+    fn test_sysfs_path() {
+        assert_eq!(sysfs_path("/dev/spidev0.0").unwrap(),
+                   Path::new("/sys/class/spidev/spidev0.0"));
+        assert_eq!(sysfs_path("/dev/spidev1.2").unwrap(),
+                   Path::new("/sys/class/spidev/spidev1.2"));
+        assert!(sysfs_path("/").is_err());
+    }
+
+    #[test]
+    fn test_controller_info() {
+        let root = scratch_dir("controller-info");
+        let info = controller_info_at(&fake_spidev(&root)).unwrap();
+        assert_eq!(info,
+                   ControllerInfo {
+                       controller: "spi0".to_string(),
+                       driver: Some("spi-bcm2835".to_string()),
+                       modalias: Some("spi:spidev".to_string()),
+                       slave: false,
+                   });
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_controller_info_slave() {
+        let root = scratch_dir("controller-info-slave");
+        let info = controller_info_at(&fake_spidev_in(&root, "spi_slave")).unwrap();
+        assert_eq!(info.controller, "spi0");
+        assert!(info.slave);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_base_clock_hz() {
+        let root = scratch_dir("base-clock");
+        let class_dir = fake_spidev(&root);
+        let err = base_clock_hz_at(&class_dir).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("clock-frequency"));
+
+        let of_node = root.join("devices/platform/soc/fe204000.spi/of_node");
+        fs::create_dir(&of_node).unwrap();
+        fs::write(of_node.join("clock-frequency"), 250_000_000u32.to_be_bytes()).unwrap();
+        assert_eq!(base_clock_hz_at(&class_dir).unwrap(), 250_000_000);
+        fs::write(of_node.join("clock-frequency"), 5_000_000_000u64.to_be_bytes()).unwrap();
+        assert_eq!(base_clock_hz_at(&class_dir).unwrap(), 5_000_000_000);
+        fs::write(of_node.join("clock-frequency"), [0; 3]).unwrap();
+        assert_eq!(base_clock_hz_at(&class_dir).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_bufsiz() {
+        let root = scratch_dir("bufsiz");
+        let path = root.join("bufsiz");
+        assert_eq!(bufsiz_at(&path), DEFAULT_BUFSIZ);
+        fs::write(&path, "65536\n").unwrap();
+        assert_eq!(bufsiz_at(&path), 65536);
+        fs::write(&path, "0\n").unwrap();
+        assert_eq!(bufsiz_at(&path), DEFAULT_BUFSIZ);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_controller_info_missing() {
+        let root = scratch_dir("controller-info-missing");
+        assert!(controller_info_at(&root.join("spidev0.0")).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_modalias() {
+        let root = scratch_dir("modalias");
+        let class_dir = fake_spidev(&root);
+        let spi_devices = root.join("bus/spi/devices");
+        assert_eq!(modalias_at(&class_dir, &spi_devices).unwrap(), "spi:spidev");
+
+        // without the attribute behind the class entry, the bus listing is
+        // used instead
+        fs::remove_file(class_dir.join("device/modalias")).unwrap();
+        assert!(modalias_at(&class_dir, &spi_devices).is_err());
+        fs::create_dir_all(spi_devices.join("spi0.0")).unwrap();
+        fs::write(spi_devices.join("spi0.0/modalias"), "spi:mydevice\n").unwrap();
+        assert_eq!(modalias_at(&class_dir, &spi_devices).unwrap(), "spi:mydevice");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_split_modalias() {
+        assert_eq!(split_modalias("spi:mydevice"), Some(("spi", "mydevice")));
+        assert_eq!(split_modalias("of:NspidevT(null)Crohm,dh2228fv"),
+                   Some(("of", "NspidevT(null)Crohm,dh2228fv")));
+        assert_eq!(split_modalias("mydevice"), None);
+        assert_eq!(split_modalias(":mydevice"), None);
+    }
+
+    #[test]
+    fn test_statistics() {
+        let root = scratch_dir("statistics");
+        let class_dir = fake_spidev(&root);
+        let dir = class_dir.join("device/statistics");
+        fs::create_dir(&dir).unwrap();
+        let counters = [("messages", "12"), ("transfers", "30"), ("errors", "1"),
+                        ("timedout", "0"), ("bytes", "4096"), ("bytes_tx", "1024"),
+                        ("bytes_rx", "3072"), ("spi_sync", "12"),
+                        ("transfer_bytes_histo_0-1", "4")];
+        for &(name, value) in &counters {
+            fs::write(dir.join(name), format!("{}\n", value)).unwrap();
+        }
+        assert_eq!(statistics_at(&dir).unwrap(),
+                   Some(Statistics {
+                       messages: 12,
+                       transfers: 30,
+                       errors: 1,
+                       timedout: 0,
+                       bytes: 4096,
+                       bytes_tx: 1024,
+                       bytes_rx: 3072,
+                   }));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_statistics_unsupported() {
+        let root = scratch_dir("statistics-unsupported");
+        let class_dir = fake_spidev(&root);
+        assert_eq!(statistics_at(&class_dir.join("device/statistics")).unwrap(), None);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn test_controller_info_device() {
+        println!("{:?}", test_device().controller_info().unwrap());
+    }
+
+    #[test]
+    #[ignore]
+    fn test_modalias_device() {
+        let modalias = test_device().modalias().unwrap();
+        assert!(split_modalias(&modalias).is_some(), "unexpected modalias {:?}", modalias);
+    }
+}
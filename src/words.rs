@@ -0,0 +1,320 @@
+// Copyright 2015, Paul Osborne <osbpau@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/license/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option.  This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Word sizes and iterators over the words of a receive buffer
+//!
+//! For `bits_per_word` of 9 to 16 spidev stores each word in two bytes,
+//! and for 17 to 32 in four bytes, in the CPU's native byte order.  The
+//! iterators here combine those bytes back into words.  A trailing
+//! partial word is ignored.
+//!
+//! Controllers that only shift 8-bit words see a multi-byte word as its
+//! bytes in buffer order, so a `ByteOrder` can be chosen in place of the
+//! native one to match what the device expects.
+
+use std::convert::TryFrom;
+use std::io;
+use std::slice::ChunksExact;
+
+macro_rules! word_sizes {
+    ($($name:ident = $bits:expr),*) => {
+        /// The number of bits in each SPI word
+        ///
+        /// Controllers support different subsets of these; 8 bits is
+        /// universally supported.  Each word occupies `bytes()` bytes of a
+        /// transfer buffer.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        #[repr(u8)]
+        pub enum WordSize {
+            $( $name = $bits, )*
+        }
+
+        impl WordSize {
+            /// Every word size, from 1 to 32 bits
+            pub const ALL: [WordSize; 32] = [$( WordSize::$name, )*];
+        }
+    }
+}
+
+word_sizes!(Bits1 = 1, Bits2 = 2, Bits3 = 3, Bits4 = 4, Bits5 = 5, Bits6 = 6, Bits7 = 7,
+            Bits8 = 8, Bits9 = 9, Bits10 = 10, Bits11 = 11, Bits12 = 12, Bits13 = 13,
+            Bits14 = 14, Bits15 = 15, Bits16 = 16, Bits17 = 17, Bits18 = 18, Bits19 = 19,
+            Bits20 = 20, Bits21 = 21, Bits22 = 22, Bits23 = 23, Bits24 = 24, Bits25 = 25,
+            Bits26 = 26, Bits27 = 27, Bits28 = 28, Bits29 = 29, Bits30 = 30, Bits31 = 31,
+            Bits32 = 32);
+
+impl WordSize {
+    /// The number of bits, as passed to the kernel
+    pub fn bits(self) -> u8 {
+        self as u8
+    }
+
+    /// The number of buffer bytes each word occupies
+    pub fn bytes(self) -> usize {
+        match self.bits() {
+            1..=8 => 1,
+            9..=16 => 2,
+            _ => 4,
+        }
+    }
+}
+
+impl Default for WordSize {
+    fn default() -> Self {
+        WordSize::Bits8
+    }
+}
+
+impl From<WordSize> for u8 {
+    fn from(size: WordSize) -> u8 {
+        size.bits()
+    }
+}
+
+impl TryFrom<u8> for WordSize {
+    type Error = io::Error;
+
+    /// Convert a `bits_per_word` value
+    ///
+    /// As in the kernel, zero means 8 bits.  Values over 32 are rejected
+    /// with an error of kind `InvalidInput`.
+    fn try_from(bits: u8) -> io::Result<WordSize> {
+        match bits {
+            0 => Ok(WordSize::Bits8),
+            1..=32 => Ok(WordSize::ALL[bits as usize - 1]),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                    "bits_per_word must be at most 32")),
+        }
+    }
+}
+
+/// The order of the bytes of a multi-byte word in a transfer buffer
+///
+/// The default is `Native`, which is what the kernel expects for
+/// `bits_per_word` over 8.  With 8-bit words the bytes are shifted out in
+/// buffer order, so `Big` sends the most significant byte first and
+/// `Little` the least significant byte first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ByteOrder {
+    Little,
+    Big,
+    Native,
+}
+
+impl Default for ByteOrder {
+    fn default() -> Self {
+        ByteOrder::Native
+    }
+}
+
+impl ByteOrder {
+    /// The bytes of a `u16` in this order
+    pub fn u16_to_bytes(self, word: u16) -> [u8; 2] {
+        match self {
+            ByteOrder::Little => word.to_le_bytes(),
+            ByteOrder::Big => word.to_be_bytes(),
+            ByteOrder::Native => word.to_ne_bytes(),
+        }
+    }
+
+    /// A `u16` from its bytes in this order
+    pub fn u16_from_bytes(self, bytes: [u8; 2]) -> u16 {
+        match self {
+            ByteOrder::Little => u16::from_le_bytes(bytes),
+            ByteOrder::Big => u16::from_be_bytes(bytes),
+            ByteOrder::Native => u16::from_ne_bytes(bytes),
+        }
+    }
+
+    /// The bytes of a `u32` in this order
+    pub fn u32_to_bytes(self, word: u32) -> [u8; 4] {
+        match self {
+            ByteOrder::Little => word.to_le_bytes(),
+            ByteOrder::Big => word.to_be_bytes(),
+            ByteOrder::Native => word.to_ne_bytes(),
+        }
+    }
+
+    /// A `u32` from its bytes in this order
+    pub fn u32_from_bytes(self, bytes: [u8; 4]) -> u32 {
+        match self {
+            ByteOrder::Little => u32::from_le_bytes(bytes),
+            ByteOrder::Big => u32::from_be_bytes(bytes),
+            ByteOrder::Native => u32::from_ne_bytes(bytes),
+        }
+    }
+
+    /// Lay out `words` in a transfer buffer in this order
+    pub fn encode_u16(self, words: &[u16]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(words.len() * 2);
+        for &word in words {
+            buf.extend_from_slice(&self.u16_to_bytes(word));
+        }
+        buf
+    }
+
+    /// Lay out `words` in a transfer buffer in this order
+    pub fn encode_u32(self, words: &[u32]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(words.len() * 4);
+        for &word in words {
+            buf.extend_from_slice(&self.u32_to_bytes(word));
+        }
+        buf
+    }
+}
+
+/// An iterator over a buffer as `u16` words
+///
+/// Returned by `SpidevTransfer::rx_words_u16`, which uses native byte
+/// order.
+#[derive(Debug, Clone)]
+pub struct WordsU16<'a> {
+    chunks: ChunksExact<'a, u8>,
+    order: ByteOrder,
+}
+
+impl<'a> WordsU16<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        WordsU16::with_order(buf, ByteOrder::Native)
+    }
+
+    /// Iterate over words stored in `order`
+    pub fn with_order(buf: &'a [u8], order: ByteOrder) -> Self {
+        WordsU16 {
+            chunks: buf.chunks_exact(2),
+            order,
+        }
+    }
+}
+
+impl<'a> Iterator for WordsU16<'a> {
+    type Item = u16;
+
+    fn next(&mut self) -> Option<u16> {
+        let order = self.order;
+        self.chunks.next().map(|word| order.u16_from_bytes([word[0], word[1]]))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.chunks.size_hint()
+    }
+}
+
+impl<'a> ExactSizeIterator for WordsU16<'a> {}
+
+/// An iterator over a buffer as `u32` words
+///
+/// Returned by `SpidevTransfer::rx_words_u32`, which uses native byte
+/// order.
+#[derive(Debug, Clone)]
+pub struct WordsU32<'a> {
+    chunks: ChunksExact<'a, u8>,
+    order: ByteOrder,
+}
+
+impl<'a> WordsU32<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        WordsU32::with_order(buf, ByteOrder::Native)
+    }
+
+    /// Iterate over words stored in `order`
+    pub fn with_order(buf: &'a [u8], order: ByteOrder) -> Self {
+        WordsU32 {
+            chunks: buf.chunks_exact(4),
+            order,
+        }
+    }
+}
+
+impl<'a> Iterator for WordsU32<'a> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        let order = self.order;
+        self.chunks.next().map(|word| order.u32_from_bytes([word[0], word[1], word[2], word[3]]))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.chunks.size_hint()
+    }
+}
+
+impl<'a> ExactSizeIterator for WordsU32<'a> {}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryFrom;
+    use super::{ByteOrder, WordSize, WordsU16, WordsU32};
+
+    #[test]
+    fn test_word_size_bytes() {
+        for (i, &size) in WordSize::ALL.iter().enumerate() {
+            let bits = i as u8 + 1;
+            assert_eq!(size.bits(), bits);
+            assert_eq!(u8::from(size), bits);
+            assert_eq!(WordSize::try_from(bits).unwrap(), size);
+            let bytes = if bits <= 8 {
+                1
+            } else if bits <= 16 {
+                2
+            } else {
+                4
+            };
+            assert_eq!(size.bytes(), bytes);
+        }
+        assert_eq!(WordSize::Bits9.bytes(), 2);
+        assert_eq!(WordSize::Bits24.bytes(), 4);
+    }
+
+    #[test]
+    fn test_word_size_try_from() {
+        assert_eq!(WordSize::try_from(0).unwrap(), WordSize::Bits8);
+        assert!(WordSize::try_from(33).is_err());
+        assert_eq!(WordSize::default(), WordSize::Bits8);
+    }
+
+    #[test]
+    fn test_words_u16() {
+        let mut buf = Vec::new();
+        for word in &[0x1ffu16, 0x0102, 0xabcd] {
+            buf.extend_from_slice(&word.to_ne_bytes());
+        }
+        // trailing partial word
+        buf.push(0xff);
+        let words = WordsU16::new(&buf);
+        assert_eq!(words.len(), 3);
+        assert_eq!(words.collect::<Vec<_>>(), [0x1ff, 0x0102, 0xabcd]);
+    }
+
+    #[test]
+    fn test_words_u32() {
+        let mut buf = Vec::new();
+        for word in &[0x00ab_cdefu32, 0x1_ffff] {
+            buf.extend_from_slice(&word.to_ne_bytes());
+        }
+        assert_eq!(WordsU32::new(&buf).collect::<Vec<_>>(), [0x00ab_cdef, 0x1_ffff]);
+        assert_eq!(WordsU32::new(&buf[..3]).count(), 0);
+    }
+
+    #[test]
+    fn test_byte_order_round_trip() {
+        let words16 = [0x0102u16, 0xabcd, 0x01ff];
+        let words32 = [0x0102_0304u32, 0x00ab_cdef];
+        for &order in &[ByteOrder::Little, ByteOrder::Big, ByteOrder::Native] {
+            let buf = order.encode_u16(&words16);
+            assert_eq!(WordsU16::with_order(&buf, order).collect::<Vec<_>>(), words16);
+            let buf = order.encode_u32(&words32);
+            assert_eq!(WordsU32::with_order(&buf, order).collect::<Vec<_>>(), words32);
+        }
+        assert_eq!(ByteOrder::Big.encode_u16(&[0x0102]), [0x01, 0x02]);
+        assert_eq!(ByteOrder::Little.encode_u16(&[0x0102]), [0x02, 0x01]);
+        assert_eq!(ByteOrder::Big.encode_u32(&[0x0102_0304]), [0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(ByteOrder::Native.encode_u32(&[0x0102_0304]), 0x0102_0304u32.to_ne_bytes());
+        assert_eq!(ByteOrder::default(), ByteOrder::Native);
+    }
+}